@@ -0,0 +1,171 @@
+use std::path::{Path, PathBuf};
+
+use crate::{diagnostic::Diagnostic, token::Span};
+
+pub const CONFIG_FILE_NAME: &str = "sash.toml";
+
+/// Project settings read from a `sash.toml` file, a small subset of TOML:
+///
+/// ```toml
+/// [build]
+/// strict = true
+/// ifs = "\n\t"
+/// ```
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Config {
+    pub strict: Option<bool>,
+    pub ifs: Option<String>,
+}
+
+enum Value {
+    Boolean(bool),
+    String(String),
+}
+
+impl Config {
+    /// Finds the `sash.toml` in the directory of `path` or the closest of its parents.
+    pub fn find(path: &Path) -> Option<PathBuf> {
+        path.ancestors()
+            .skip(1)
+            .map(|directory| directory.join(CONFIG_FILE_NAME))
+            .find(|candidate| candidate.is_file())
+    }
+
+    /// Parses the contents of a `sash.toml` file.
+    ///
+    /// # Errors
+    ///
+    /// Returns a diagnostic for every line that is not valid or sets an unknown key.
+    pub fn parse(source: &str) -> Result<Self, Vec<Diagnostic>> {
+        let mut config = Config::default();
+        let mut diagnostics = Vec::new();
+        let mut in_build = false;
+        let mut line_start = 0;
+
+        for (number, line) in source.split_inclusive('\n').enumerate() {
+            let start = line_start;
+            line_start += line.len();
+
+            let content = strip_comment(line);
+            let trimmed = content.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+
+            let offset = content.len() - content.trim_start().len();
+            let span = Span {
+                start: start + offset,
+                end: start + offset + trimmed.len(),
+                line: number + 1,
+                column: offset + 1,
+            };
+
+            if let Some(name) = trimmed
+                .strip_prefix('[')
+                .and_then(|name| name.strip_suffix(']'))
+            {
+                in_build = name.trim() == "build";
+
+                if !in_build {
+                    diagnostics.push(
+                        Diagnostic::error(format!("Unknown section [{}]", name.trim()))
+                            .with_label(span, "expected [build]"),
+                    );
+                }
+                continue;
+            }
+
+            let Some((key, value)) = trimmed.split_once('=') else {
+                diagnostics.push(
+                    Diagnostic::error("Expected key = value").with_label(span, "invalid line"),
+                );
+                continue;
+            };
+
+            if !in_build {
+                diagnostics.push(
+                    Diagnostic::error("Settings must be in the [build] section")
+                        .with_label(span, "outside of a section"),
+                );
+                continue;
+            }
+
+            let Some(value) = parse_value(value.trim()) else {
+                diagnostics.push(
+                    Diagnostic::error("Expected true, false or a quoted string")
+                        .with_label(span, "invalid value"),
+                );
+                continue;
+            };
+
+            match (key.trim(), value) {
+                ("strict", Value::Boolean(strict)) => config.strict = Some(strict),
+                ("ifs", Value::String(ifs)) => config.ifs = Some(ifs),
+                ("strict", _) => diagnostics.push(
+                    Diagnostic::error("strict must be true or false")
+                        .with_label(span, "expected a boolean"),
+                ),
+                ("ifs", _) => diagnostics.push(
+                    Diagnostic::error("ifs must be a string").with_label(span, "expected a string"),
+                ),
+                (key, _) => diagnostics.push(
+                    Diagnostic::error(format!("Unknown setting \"{key}\""))
+                        .with_label(span, "not a build setting")
+                        .with_note("the known settings are strict and ifs"),
+                ),
+            }
+        }
+
+        if diagnostics.is_empty() {
+            Ok(config)
+        } else {
+            Err(diagnostics)
+        }
+    }
+}
+
+/// Removes a `#` comment from the end of `line`, unless it is inside of a string.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (index, char) in line.char_indices() {
+        match char {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..index],
+            _ => {}
+        }
+    }
+
+    line
+}
+
+fn parse_value(value: &str) -> Option<Value> {
+    match value {
+        "true" => return Some(Value::Boolean(true)),
+        "false" => return Some(Value::Boolean(false)),
+        _ => {}
+    }
+
+    let mut chars = value.strip_prefix('"')?.strip_suffix('"')?.chars();
+    let mut string = String::new();
+
+    while let Some(char) = chars.next() {
+        string.push(match char {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '"' => '"',
+                '\\' => '\\',
+                _ => return None,
+            },
+            '"' => return None,
+            char => char,
+        });
+    }
+
+    Some(Value::String(string))
+}
@@ -0,0 +1,224 @@
+use std::{fmt::Write, slice, vec};
+
+use crate::token::Span;
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+    pub primary: bool,
+}
+
+/// A problem found in the source, pointing at the code that caused it.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.into(),
+            labels: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn warning(message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(message)
+        }
+    }
+
+    #[must_use]
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+            primary: true,
+        });
+        self
+    }
+
+    #[must_use]
+    pub fn with_secondary_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+            primary: false,
+        });
+        self
+    }
+
+    #[must_use]
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// Renders the diagnostic in the style of rustc, quoting the lines of
+    /// `source` that the labels point at. With `color`, the output is
+    /// highlighted with ANSI escape codes.
+    pub fn render(&self, source: &str, path: &str, color: bool) -> String {
+        let mut output = String::new();
+
+        let (severity, severity_style) = match self.severity {
+            Severity::Error => ("error", RED),
+            Severity::Warning => ("warning", YELLOW),
+        };
+        let paint = |text: &str, style: &str| {
+            if color {
+                format!("{style}{text}{RESET}")
+            } else {
+                text.to_string()
+            }
+        };
+
+        writeln!(
+            output,
+            "{}{}",
+            paint(severity, severity_style),
+            paint(&format!(": {}", self.message), BOLD)
+        )
+        .unwrap();
+
+        let mut labels: Vec<&Label> = self.labels.iter().collect();
+        labels.sort_by_key(|label| (label.span.start, !label.primary));
+
+        let gutter = labels
+            .iter()
+            .map(|label| label.span.line.to_string().len())
+            .max()
+            .unwrap_or(0);
+        let padding = " ".repeat(gutter);
+
+        if let Some(location) = labels.iter().find(|label| label.primary).or(labels.first()) {
+            writeln!(
+                output,
+                "{padding}{} {path}:{}:{}",
+                paint("-->", BLUE),
+                location.span.line,
+                location.span.column
+            )
+            .unwrap();
+        }
+
+        let bar = paint("|", BLUE);
+
+        if !labels.is_empty() {
+            writeln!(output, "{padding} {bar}").unwrap();
+        }
+
+        let lines: Vec<&str> = source.lines().collect();
+        let mut previous_line = None;
+
+        for label in &labels {
+            let line = lines.get(label.span.line.saturating_sub(1)).unwrap_or(&"");
+
+            if previous_line != Some(label.span.line) {
+                let number = format!("{:>gutter$}", label.span.line);
+                writeln!(output, "{} {bar} {line}", paint(&number, BLUE)).unwrap();
+                previous_line = Some(label.span.line);
+            }
+
+            let offset = label.span.column.saturating_sub(1);
+            let width = source
+                .get(label.span.start..label.span.end)
+                .map_or(0, |text| text.lines().next().unwrap_or("").chars().count())
+                .max(1);
+            let (marker, style) = if label.primary {
+                ("^", severity_style)
+            } else {
+                ("-", BLUE)
+            };
+
+            writeln!(
+                output,
+                "{padding} {bar} {}{}",
+                " ".repeat(offset),
+                paint(
+                    &format!("{} {}", marker.repeat(width), label.message),
+                    style
+                )
+            )
+            .unwrap();
+        }
+
+        if !labels.is_empty() && !self.notes.is_empty() {
+            writeln!(output, "{padding} {bar}").unwrap();
+        }
+
+        for note in &self.notes {
+            writeln!(output, "{padding} {} {note}", paint("= note:", BOLD)).unwrap();
+        }
+
+        output
+    }
+}
+
+/// All problems found while compiling a script.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics(Vec<Diagnostic>);
+
+impl Diagnostics {
+    pub fn iter(&self) -> slice::Iter<'_, Diagnostic> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Renders every diagnostic, separated by blank lines.
+    pub fn render(&self, source: &str, path: &str, color: bool) -> String {
+        self.iter()
+            .map(|diagnostic| diagnostic.render(source, path, color))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl From<Vec<Diagnostic>> for Diagnostics {
+    fn from(diagnostics: Vec<Diagnostic>) -> Self {
+        Diagnostics(diagnostics)
+    }
+}
+
+impl IntoIterator for Diagnostics {
+    type Item = Diagnostic;
+    type IntoIter = vec::IntoIter<Diagnostic>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Diagnostics {
+    type Item = &'a Diagnostic;
+    type IntoIter = slice::Iter<'a, Diagnostic>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
@@ -1,16 +1,18 @@
 use std::fmt::Write;
 
 use crate::{
-    formatter,
-    token::{Kind, Token},
+    formatter, quoting,
+    token::{Kind, Span, Token, UNARY_PRECEDENCE},
+    types::Comparison,
 };
 
 #[derive(Debug, Clone)]
 pub enum Expression {
     Value(ValueExpr),
     Arithmetic(ArithmeticExpr),
-    Parenthesis(ParenthesisExpr),
+    Unary(UnaryExpr),
     Condition(ConditionExpr),
+    StatusCondition(StatusConditionExpr),
     FnCall(FnCall),
     FnChain(FnChain),
     VarAssignment(VarAssignmentExpr),
@@ -19,6 +21,37 @@ pub enum Expression {
     ElifStatement(ElifStatementExpr),
     ElseStatement(ElseStatementExpr),
     WhileStatement(WhileStatementExpr),
+    Loop(LoopExpr),
+    DoWhile(DoWhileExpr),
+    LoopControl(LoopControlExpr),
+    ForStatement(ForStatementExpr),
+    Range(RangeExpr),
+    FunctionDecl(FunctionDeclExpr),
+    Return(ReturnExpr),
+    Array(ArrayExpr),
+    ArrayRef(ArrayRefExpr),
+    Index(IndexExpr),
+    Slice(SliceExpr),
+    IndexAssignment(IndexAssignmentExpr),
+    Length(LengthExpr),
+    Push(PushExpr),
+    Map(MapExpr),
+    MapRef(MapRefExpr),
+    Keys(KeysExpr),
+    Has(HasExpr),
+    Logical(LogicalExpr),
+    Not(NotExpr),
+    Boolean(BooleanExpr),
+    Error(ErrorExpr),
+    Commented(CommentedExpr),
+    InterpolatedString(InterpolatedStringExpr),
+    Run(RunExpr),
+    ChainBuiltin(ChainBuiltinExpr),
+    Spawn(SpawnExpr),
+    Parallel(ParallelExpr),
+    Defer(DeferExpr),
+    Called(CalledExpr),
+    FunctionCondition(FunctionConditionExpr),
 }
 
 impl Expression {
@@ -26,8 +59,9 @@ impl Expression {
         match self {
             Self::Value(expr) => expr.value.write(),
             Self::Arithmetic(expr) => expr.write(),
-            Self::Parenthesis(expr) => expr.write(),
+            Self::Unary(expr) => expr.write(),
             Self::Condition(expr) => expr.write(),
+            Self::StatusCondition(expr) => expr.write(),
             Self::FnCall(fn_call) => fn_call.write(),
             Self::FnChain(fn_chain) => fn_chain.write(),
             Self::VarAssignment(expr) => expr.write(),
@@ -36,6 +70,117 @@ impl Expression {
             Self::ElifStatement(expr) => expr.write(),
             Self::ElseStatement(expr) => expr.write(),
             Self::WhileStatement(expr) => expr.write(),
+            Self::Loop(expr) => expr.write(),
+            Self::DoWhile(expr) => expr.write(),
+            Self::LoopControl(expr) => expr.write(),
+            Self::ForStatement(expr) => expr.write(),
+            Self::Range(expr) => expr.write(),
+            Self::FunctionDecl(expr) => expr.write(),
+            Self::Return(expr) => expr.write(),
+            Self::Array(expr) => expr.write(),
+            Self::ArrayRef(expr) => expr.write(),
+            Self::Index(expr) => expr.write(),
+            Self::Slice(expr) => expr.write(),
+            Self::IndexAssignment(expr) => expr.write(),
+            Self::Length(expr) => expr.write(),
+            Self::Push(expr) => expr.write(),
+            Self::Map(expr) => expr.write(),
+            Self::MapRef(expr) => expr.write(),
+            Self::Keys(expr) => expr.write(),
+            Self::Has(expr) => expr.write(),
+            Self::Logical(expr) => expr.write(),
+            Self::Not(expr) => expr.write(),
+            Self::Boolean(expr) => expr.write(),
+            Self::Error(expr) => expr.write(),
+            Self::Commented(expr) => expr.write(),
+            Self::InterpolatedString(expr) => expr.write(),
+            Self::ChainBuiltin(expr) => expr.write(),
+            Self::Spawn(expr) => expr.write(),
+            Self::Parallel(expr) => expr.write(),
+            Self::Defer(expr) => expr.write(),
+            Self::Called(expr) => expr.write(),
+            Self::FunctionCondition(expr) => expr.write(),
+            Self::Run(_) => unreachable!("run!() is written by the declaration storing its result"),
+        }
+    }
+
+    /// Whether the expression is only comments, which bash does not count as a command.
+    pub fn is_comment(&self) -> bool {
+        matches!(
+            self,
+            Self::Commented(CommentedExpr {
+                statement: None,
+                ..
+            })
+        )
+    }
+
+    /// Whether the expression declares a function, with or without comments before it.
+    pub fn is_function_declaration(&self) -> bool {
+        match self {
+            Self::FunctionDecl(_) => true,
+            Self::Commented(CommentedExpr {
+                statement: Some(statement),
+                ..
+            }) => statement.is_function_declaration(),
+            _ => false,
+        }
+    }
+
+    /// Whether the expression evaluates to a whole array rather than a single value.
+    pub fn is_array(&self) -> bool {
+        matches!(
+            self,
+            Self::Array(_) | Self::ArrayRef(_) | Self::Slice(_) | Self::Keys(_)
+        )
+    }
+
+    /// Whether the expression is a chain followed by saving the statuses of
+    /// its commands, which has to be grouped to be a part of a condition.
+    fn saves_statuses(&self) -> bool {
+        matches!(
+            self,
+            Self::FnChain(FnChain {
+                save_statuses: true,
+                ..
+            })
+        )
+    }
+
+    /// Writes a single element of an array or map literal as one word.
+    fn write_element(&self) -> String {
+        formatter::get_args_as_string(std::slice::from_ref(self))
+    }
+
+    /// Writes the expression as an operand inside of `$(( ))` or `(( ))`,
+    /// where variables do not need to be expanded with `$`.
+    pub fn write_arithmetic(&self) -> String {
+        match self {
+            Self::Value(ValueExpr { value }) if value.kind == Kind::Status => "$?".into(),
+            Self::Value(ValueExpr { value }) => value.lexeme.clone(),
+            Self::Arithmetic(expr) => expr.write_arithmetic(),
+            Self::Unary(expr) => expr.write_arithmetic(),
+            Self::FnCall(_) | Self::FnChain(_) => format!("$({})", self.write()),
+            _ => self.write(),
+        }
+    }
+
+    /// Binding power of the expression's outermost operator, used to decide
+    /// where parentheses are needed when it is written as an operand.
+    fn precedence(&self) -> u8 {
+        match self {
+            Self::Arithmetic(expr) => expr.operator.kind.precedence().unwrap_or(0),
+            Self::Unary(_) => UNARY_PRECEDENCE,
+            _ => u8::MAX,
+        }
+    }
+
+    fn write_assigned_value(&self) -> String {
+        match self {
+            Self::FnCall(_) | Self::FnChain(_) => quoting::command_substitution(&self.write()),
+            Self::Array(_) | Self::Map(_) => self.write(),
+            expr if expr.is_array() => format!("({})", expr.write()),
+            _ => self.write(),
         }
     }
 }
@@ -49,34 +194,60 @@ pub struct FnCall {
     pub name: Token,
     pub args: Vec<Expression>,
     pub command: bool,
+    pub user_defined: bool,
+}
+
+impl FnCall {
+    /// Whether the call is `wait()` or `wait_all()`, whose value is the exit
+    /// status of the jobs it waits for.
+    pub fn waits(&self) -> bool {
+        !self.command
+            && !self.user_defined
+            && matches!(self.name.lexeme.as_str(), "wait" | "wait_all")
+    }
+
+    /// Writes the call storing its exit status in `name`. It cannot be read
+    /// in a command substitution, since a subshell cannot wait for the jobs
+    /// of the script.
+    fn write_status_into(&self, name: &str, local: bool) -> String {
+        let declare = if local {
+            format!("local {name}\n")
+        } else {
+            String::new()
+        };
+
+        format!(
+            "{declare}{} && {name}=0 || {name}=$?",
+            self.write().trim_end()
+        )
+    }
 }
 
 impl Expr for FnCall {
     fn write(&self) -> String {
         let args_string = formatter::get_args_as_string(&self.args);
 
-        if self.command {
-            format!("{} {}", self.name.write(), args_string)
+        if self.command || self.user_defined {
+            format!("{} {}", self.name.lexeme, args_string)
         } else {
             match self.name.lexeme.as_str() {
-                "print" => format!("echo -e {args_string}"),
+                "print" => format!("echo {args_string}"),
                 "compress" => format!("tar -caf {args_string}"),
                 "decompress" => format!("tar -xf {args_string}"),
                 "ls_archive" => format!("tar -tvf {args_string}"),
+                "exit" if self.args.is_empty() => "exit".into(),
+                "exit" => format!("exit {args_string}"),
+                "wait" => format!("wait {args_string}"),
+                "wait_all" => "wait".into(),
                 "red" | "green" | "yellow" | "blue" | "magenta" | "cyan" => {
-                    match self.args.get(0).unwrap() {
-                        Expression::Value(ValueExpr { value }) => match value.kind {
-                            Kind::String => {
-                                formatter::colorize_string(&self.name.lexeme, &value.lexeme)
-                            }
-                            _ => formatter::colorize_string(&self.name.lexeme, &value.write()),
-                        },
-                        _ => {
-                            panic!("Color functions can only take value expression as an argument")
+                    match self.args.first().unwrap() {
+                        Expression::Value(ValueExpr { value }) => {
+                            formatter::colorize_string(&self.name.lexeme, &value.write())
                         }
+                        _ => unreachable!("the parser checks the arguments of color functions"),
                     }
                 }
-                _ => panic!("Build-in function \"{}\" not supported!", self.name.lexeme),
+                _ => unreachable!("the parser rejects unknown functions"),
             }
         }
     }
@@ -85,24 +256,147 @@ impl Expr for FnCall {
 #[derive(Debug, Clone)]
 pub struct FnChain {
     pub invocations: Vec<Expression>,
+    pub redirects: Vec<Redirect>,
+    /// Whether the chain ends with `.lines()`, which makes its value an
+    /// array of the lines of its output.
+    pub lines: bool,
+    /// Whether the statuses of the commands are saved for `statuses` after
+    /// the chain runs, as it does when it is a statement or a condition.
+    pub save_statuses: bool,
+}
+
+impl FnChain {
+    /// Writes the chain storing its lines in the array `name`.
+    fn write_lines_into(&self, name: &str, local: bool) -> String {
+        let mapfile = format!("mapfile -t {name} < <({})", self.write());
+
+        if local {
+            format!("local -a {name}\n{mapfile}")
+        } else {
+            mapfile
+        }
+    }
 }
 
 impl Expr for FnChain {
     fn write(&self) -> String {
         let mut output = String::new();
 
-        let mut iter = self.invocations.iter().peekable();
-        while let Some(invocation) = iter.next() {
-            output.write_str(&invocation.write()).unwrap();
+        let mut iter = self.invocations.iter().enumerate().peekable();
+        while let Some((stage, invocation)) = iter.next() {
+            let mut redirects: Vec<&Redirect> = self
+                .redirects
+                .iter()
+                .filter(|redirect| redirect.stage == stage)
+                .collect();
+
+            if redirects.is_empty() {
+                output.write_str(&invocation.write()).unwrap();
+            } else {
+                // Stdout is redirected before stderr is sent to it, so that
+                // both end up in the file regardless of the order in sash
+                redirects.sort_by_key(|redirect| redirect.kind);
+
+                output.write_str(invocation.write().trim_end()).unwrap();
+                for redirect in redirects {
+                    write!(output, " {}", redirect.write()).unwrap();
+                }
+            }
+
             if iter.peek().is_some() {
                 output.write_str(" | ").unwrap();
             }
         }
 
+        if self.save_statuses {
+            output.write_str("\n__save_statuses $?").unwrap();
+        }
+
         output
     }
 }
 
+/// Makes a pipeline fail when any of its commands fails, and defines the
+/// helper saving the statuses of the commands of the last pipeline for
+/// `statuses`, since `PIPESTATUS` changes with every command after it. The
+/// helper returns the status it is given, so that `status` is not changed.
+pub const PIPELINE_RUNTIME: &str = r#"set -o pipefail
+__statuses=()
+__save_statuses() {
+    __statuses=("${PIPESTATUS[@]}")
+    return "$1"
+}
+"#;
+
+/// Where a redirect sends the output of a command or reads its input from,
+/// in the order the redirects of one command are written in.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+pub enum RedirectKind {
+    FromFile,
+    FromString,
+    ToFile,
+    AppendTo,
+    StderrToStdout,
+    StderrTo,
+}
+
+impl RedirectKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "from_file" => Some(Self::FromFile),
+            "from_string" => Some(Self::FromString),
+            "to_file" => Some(Self::ToFile),
+            "append_to" => Some(Self::AppendTo),
+            "stderr_to_stdout" => Some(Self::StderrToStdout),
+            "stderr_to" => Some(Self::StderrTo),
+            _ => None,
+        }
+    }
+
+    pub fn is_input(self) -> bool {
+        matches!(self, Self::FromFile | Self::FromString)
+    }
+
+    pub fn is_stdout(self) -> bool {
+        matches!(self, Self::ToFile | Self::AppendTo)
+    }
+
+    pub fn is_stderr(self) -> bool {
+        matches!(self, Self::StderrToStdout | Self::StderrTo)
+    }
+
+    pub fn takes_target(self) -> bool {
+        self != Self::StderrToStdout
+    }
+}
+
+/// A redirect of the command at index `stage` of a chain.
+#[derive(Debug, Clone)]
+pub struct Redirect {
+    pub kind: RedirectKind,
+    pub name: Token,
+    pub target: Option<Box<Expression>>,
+    pub stage: usize,
+}
+
+impl Redirect {
+    fn write(&self) -> String {
+        let operator = match self.kind {
+            RedirectKind::FromFile => "<",
+            RedirectKind::FromString => "<<<",
+            RedirectKind::ToFile => ">",
+            RedirectKind::AppendTo => ">>",
+            RedirectKind::StderrToStdout => return "2>&1".into(),
+            RedirectKind::StderrTo => "2>",
+        };
+
+        match &self.target {
+            Some(target) => format!("{operator} {}", target.write_element()),
+            None => unreachable!("the parser checks the arguments of redirects"),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ValueExpr {
     pub value: Token,
@@ -113,25 +407,162 @@ pub struct ArithmeticExpr {
     pub lhs: Box<Expression>,
     pub operator: Token,
     pub rhs: Box<Expression>,
+    /// Whether an operand is a float, which bash cannot calculate with.
+    pub float: bool,
 }
 
 impl ArithmeticExpr {
-    fn eval(&self) -> String {
-        format!(
-            "{} {} {}",
-            self.lhs.write(),
-            self.operator.write(),
-            match *self.rhs.clone() {
-                Expression::Arithmetic(rhs) => rhs.eval(),
-                _ => self.rhs.write(),
+    fn write_arithmetic(&self) -> String {
+        self.write_operation(&self.operator.lexeme, &mut Expression::write_arithmetic)
+    }
+
+    /// Writes the operation with `operator`, using `write_operand` for the
+    /// operands and adding parentheses where precedence requires them.
+    fn write_operation(
+        &self,
+        operator: &str,
+        write_operand: &mut dyn FnMut(&Expression) -> String,
+    ) -> String {
+        let precedence = self.operator.kind.precedence().unwrap_or(0);
+        let right_associative = self.operator.kind.is_right_associative();
+
+        let lhs = match self.lhs.precedence() {
+            lhs if lhs < precedence || (lhs == precedence && right_associative) => {
+                format!("({})", write_operand(&self.lhs))
             }
-        )
+            _ => write_operand(&self.lhs),
+        };
+
+        let rhs = match self.rhs.precedence() {
+            rhs if rhs < precedence || (rhs == precedence && !right_associative) => {
+                format!("({})", write_operand(&self.rhs))
+            }
+            _ => write_operand(&self.rhs),
+        };
+
+        format!("{lhs} {operator} {rhs}")
+    }
+
+    /// Writes the awk command printing the result of a float operation.
+    fn write_awk(&self) -> String {
+        let mut awk = AwkProgram::default();
+        let expression = awk.operand(&Expression::Arithmetic(self.clone()));
+        awk.write(&format!("printf \"{FLOAT_FORMAT}\\n\", {expression}"))
     }
 }
 
 impl Expr for ArithmeticExpr {
     fn write(&self) -> String {
-        format!("$(({}))", self.eval())
+        if self.float {
+            quoting::command_substitution(&self.write_awk())
+        } else {
+            format!("$(({}))", self.write_arithmetic())
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnaryExpr {
+    pub operator: Token,
+    pub operand: Box<Expression>,
+    /// Whether the operand is a float, which bash cannot calculate with.
+    pub float: bool,
+}
+
+impl UnaryExpr {
+    fn write_arithmetic(&self) -> String {
+        self.write_operation(&mut Expression::write_arithmetic)
+    }
+
+    fn write_operation(&self, write_operand: &mut dyn FnMut(&Expression) -> String) -> String {
+        // Unlike in sash, bash binds unary operators tighter than `**`, and
+        // `--` is a decrement, so anything but a plain value is parenthesized
+        match self.operand.as_ref() {
+            Expression::Value(_) => {
+                format!("{}{}", self.operator.lexeme, write_operand(&self.operand))
+            }
+            operand => format!("{}({})", self.operator.lexeme, write_operand(operand)),
+        }
+    }
+
+    fn write_awk(&self) -> String {
+        let mut awk = AwkProgram::default();
+        let expression = awk.operand(&Expression::Unary(self.clone()));
+        awk.write(&format!("printf \"{FLOAT_FORMAT}\\n\", {expression}"))
+    }
+}
+
+impl Expr for UnaryExpr {
+    fn write(&self) -> String {
+        if self.float {
+            quoting::command_substitution(&self.write_awk())
+        } else {
+            format!("$(({}))", self.write_arithmetic())
+        }
+    }
+}
+
+/// Format of the floats calculated by awk. Its `print` only keeps 6 digits,
+/// while 17 would show the rounding errors of binary floats, like in
+/// `0.10000000000000001`.
+const FLOAT_FORMAT: &str = "%.15g";
+
+/// An awk program that calculates with floats, which bash does not support.
+/// Values from bash are passed to it as awk variables.
+#[derive(Default)]
+struct AwkProgram {
+    variables: Vec<(String, String)>,
+}
+
+impl AwkProgram {
+    /// Writes `expression` in awk syntax, binding the bash values it uses.
+    fn operand(&mut self, expression: &Expression) -> String {
+        match expression {
+            Expression::Value(ValueExpr { value }) if value.kind == Kind::Identifier => {
+                let value = value.write();
+                match self.variables.iter().find(|(_, bound)| *bound == value) {
+                    Some((name, _)) => name.clone(),
+                    None => self.bind(value),
+                }
+            }
+            Expression::Value(ValueExpr { value })
+                if matches!(value.kind, Kind::Number | Kind::Float) =>
+            {
+                value.lexeme.clone()
+            }
+            Expression::Arithmetic(expr) => {
+                let operator = match expr.operator.kind {
+                    Kind::Power => "^",
+                    _ => &expr.operator.lexeme,
+                };
+                expr.write_operation(operator, &mut |operand| self.operand(operand))
+            }
+            Expression::Unary(expr) => expr.write_operation(&mut |operand| self.operand(operand)),
+            Expression::FnCall(_) | Expression::FnChain(_) => {
+                self.bind(quoting::command_substitution(&expression.write()))
+            }
+            expression => self.bind(expression.write()),
+        }
+    }
+
+    /// Passes `value` to awk in a variable of its own, giving back its name.
+    /// The names are generated, since the names of bash variables could be
+    /// awk keywords or builtins, like `index`.
+    fn bind(&mut self, value: String) -> String {
+        let name = format!("_{}", self.variables.len());
+        self.variables.push((name.clone(), value));
+        name
+    }
+
+    fn write(&self, action: &str) -> String {
+        let mut output = String::from("awk");
+
+        for (name, value) in &self.variables {
+            write!(output, " -v {name}={value}").unwrap();
+        }
+
+        write!(output, " 'BEGIN {{ {action} }}'").unwrap();
+        output
     }
 }
 
@@ -143,14 +574,18 @@ pub struct VarAssignmentExpr {
 
 impl Expr for VarAssignmentExpr {
     fn write(&self) -> String {
-        format!(
-            "{}={}",
-            self.name.lexeme,
-            match *self.value.clone() {
-                Expression::FnCall(fn_call) => format!("\"$({})\"", fn_call.write()),
-                _ => self.value.write(),
+        match self.value.as_ref() {
+            Expression::FnChain(chain) if chain.lines => {
+                return chain.write_lines_into(&self.name.lexeme, false);
             }
-        )
+            Expression::FnCall(call) if call.waits() => {
+                return call.write_status_into(&self.name.lexeme, false);
+            }
+            Expression::Spawn(spawn) => return spawn.write_declaration(&self.name.lexeme, false),
+            _ => {}
+        }
+
+        format!("{}={}", self.name.lexeme, self.value.write_assigned_value())
     }
 }
 
@@ -158,53 +593,124 @@ impl Expr for VarAssignmentExpr {
 pub struct VarDeclarationExpr {
     pub name: Token,
     pub value: Box<Expression>,
+    pub local: bool,
 }
 
 impl Expr for VarDeclarationExpr {
     fn write(&self) -> String {
+        match self.value.as_ref() {
+            Expression::Run(run) => return run.write_declaration(&self.name.lexeme, self.local),
+            Expression::FnChain(chain) if chain.lines => {
+                return chain.write_lines_into(&self.name.lexeme, self.local);
+            }
+            Expression::FnCall(call) if call.waits() => {
+                return call.write_status_into(&self.name.lexeme, self.local);
+            }
+            Expression::Spawn(spawn) => {
+                return spawn.write_declaration(&self.name.lexeme, self.local);
+            }
+            _ => {}
+        }
+
+        let declare = match (self.local, self.value.as_ref()) {
+            (true, Expression::Map(_)) => "local -A ",
+            (false, Expression::Map(_)) => "declare -A ",
+            (true, _) => "local ",
+            (false, _) => "",
+        };
+
         format!(
-            "{}={}",
+            "{}{}={}",
+            declare,
             self.name.lexeme,
-            match *self.value.clone() {
-                Expression::FnCall(fn_call) => format!("\"$({})\"", fn_call.write()),
-                _ => self.value.write(),
-            }
+            self.value.write_assigned_value()
         )
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct ParenthesisExpr {
-    pub value: Box<Expression>,
+pub struct ConditionExpr {
+    pub lhs: Box<Expression>,
+    pub operator: Token,
+    pub rhs: Box<Expression>,
+    pub comparison: Comparison,
 }
 
-impl Expr for ParenthesisExpr {
+impl Expr for ConditionExpr {
     fn write(&self) -> String {
-        format!(
-            "({})",
-            match *self.value.clone() {
-                Expression::Arithmetic(rhs) => rhs.eval(),
-                _ => self.value.write(),
+        match self.comparison {
+            Comparison::Float => {
+                let mut awk = AwkProgram::default();
+                let lhs = awk.operand(&self.lhs);
+                let rhs = awk.operand(&self.rhs);
+                awk.write(&format!("exit !({lhs} {} {rhs})", self.operator.lexeme))
             }
-        )
+            Comparison::Numeric => format!(
+                "(( {} {} {} ))",
+                self.lhs.write_arithmetic(),
+                self.operator.lexeme,
+                self.rhs.write_arithmetic()
+            ),
+            // [[ ]] only knows strict string ordering, so <= and >= are
+            // written as the negation of the opposite comparison
+            Comparison::String => match self.operator.kind {
+                Kind::LessEqual => format!(
+                    "[[ ! {} > {} ]]",
+                    self.lhs.write_element(),
+                    self.rhs.write_element()
+                ),
+                Kind::GreaterEqual => format!(
+                    "[[ ! {} < {} ]]",
+                    self.lhs.write_element(),
+                    self.rhs.write_element()
+                ),
+                _ => format!(
+                    "[[ {} {} {} ]]",
+                    self.lhs.write_element(),
+                    self.operator.lexeme,
+                    self.rhs.write_element()
+                ),
+            },
+        }
     }
 }
 
+/// The condition of a statement that reads `status`. The status is saved
+/// before the condition is tested, since every test in it changes `$?`.
 #[derive(Debug, Clone)]
-pub struct ConditionExpr {
-    pub lhs: Box<Expression>,
-    pub operator: Token,
-    pub rhs: Box<Expression>,
+pub struct StatusConditionExpr {
+    pub condition: Box<Expression>,
 }
 
-impl Expr for ConditionExpr {
+pub const STATUS_VARIABLE: &str = "__status";
+
+impl Expr for StatusConditionExpr {
     fn write(&self) -> String {
-        format!(
-            "[ {} {} {} ]",
-            self.lhs.write(),
-            self.operator.write(),
-            self.rhs.write()
-        )
+        format!("{STATUS_VARIABLE}=$?; {}", self.condition.write())
+    }
+}
+
+/// Writes the condition of an `elif`, which reads the status saved by the
+/// `if`, since `$?` is the status of the failed test by then.
+fn write_elif_condition(condition: &Expression) -> String {
+    match condition {
+        Expression::StatusCondition(StatusConditionExpr { condition }) => condition.write(),
+        condition => condition.write(),
+    }
+}
+
+/// Whether an `elif` in the branches after an `if` reads `status`.
+fn branches_read_status(branching: Option<&Expression>) -> bool {
+    match branching {
+        Some(Expression::ElifStatement(ElifStatementExpr {
+            condition,
+            branching,
+            ..
+        })) => {
+            matches!(condition.as_ref(), Expression::StatusCondition(_))
+                || branches_read_status(branching.as_deref())
+        }
+        _ => false,
     }
 }
 
@@ -220,9 +726,16 @@ impl Expr for IfStatementExpr {
         if self.body.is_empty() {
             String::new()
         } else {
+            let condition = match self.condition.as_ref() {
+                Expression::StatusCondition(_) => self.condition.write(),
+                condition if branches_read_status(self.branching.as_deref()) => {
+                    format!("{STATUS_VARIABLE}=$?; {}", condition.write())
+                }
+                condition => condition.write(),
+            };
+
             format!(
-                "if {}; then\n{}{}",
-                self.condition.write(),
+                "if {condition}; then\n{}{}",
                 formatter::write_formatted_expressions(&self.body),
                 match &self.branching {
                     Some(branching) => branching.write(),
@@ -247,7 +760,7 @@ impl Expr for ElifStatementExpr {
         } else {
             format!(
                 "elif {}; then\n{}{}",
-                self.condition.write(),
+                write_elif_condition(&self.condition),
                 formatter::write_formatted_expressions(&self.body),
                 match &self.branching {
                     Some(branching) => branching.write(),
@@ -295,3 +808,833 @@ impl Expr for WhileStatementExpr {
         }
     }
 }
+
+/// Writes the body of a loop or of a group, which bash does not allow to be empty.
+fn write_loop_body(body: &[Expression]) -> String {
+    if body.is_empty() {
+        "    :\n".into()
+    } else {
+        formatter::write_formatted_expressions(body)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoopExpr {
+    pub body: Vec<Expression>,
+}
+
+impl Expr for LoopExpr {
+    fn write(&self) -> String {
+        format!("while true\ndo\n{}done", write_loop_body(&self.body))
+    }
+}
+
+/// A loop that runs its body before checking the condition. The `first`
+/// flag skips the condition on the first iteration, so that `continue`
+/// still checks it, unlike with a condition at the end of the body.
+#[derive(Debug, Clone)]
+pub struct DoWhileExpr {
+    pub condition: Box<Expression>,
+    pub body: Vec<Expression>,
+    pub first: Token,
+}
+
+impl Expr for DoWhileExpr {
+    fn write(&self) -> String {
+        let first = &self.first.lexeme;
+        // The status is saved before testing the flag, which changes it
+        let (save, condition) = match self.condition.as_ref() {
+            Expression::StatusCondition(StatusConditionExpr { condition }) => {
+                (format!("{STATUS_VARIABLE}=$?; "), condition.as_ref())
+            }
+            condition => (String::new(), condition),
+        };
+        let condition = match condition {
+            Expression::Logical(_) | Expression::Called(_) => {
+                format!("{{ {}; }}", condition.write())
+            }
+            condition if condition.saves_statuses() => format!("{{ {}; }}", condition.write()),
+            condition => condition.write(),
+        };
+
+        format!(
+            "{first}=true\nwhile {save}\"${first}\" || {condition}\ndo\n    {first}=false\n{}done",
+            write_loop_body(&self.body),
+        )
+    }
+}
+
+/// A `break` or `continue` of the loop `depth` levels out from the innermost.
+#[derive(Debug, Clone)]
+pub struct LoopControlExpr {
+    pub keyword: Token,
+    pub depth: usize,
+}
+
+impl Expr for LoopControlExpr {
+    fn write(&self) -> String {
+        match self.depth {
+            1 => self.keyword.lexeme.clone(),
+            depth => format!("{} {depth}", self.keyword.lexeme),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RangeExpr {
+    pub start: Box<Expression>,
+    pub end: Box<Expression>,
+}
+
+impl Expr for RangeExpr {
+    fn write(&self) -> String {
+        format!(
+            "$(seq {} $(({} - 1)))",
+            self.start.write(),
+            self.end.write()
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ForStatementExpr {
+    pub variable: Token,
+    pub value_variable: Option<Token>,
+    pub iterable: Box<Expression>,
+    pub body: Vec<Expression>,
+}
+
+impl ForStatementExpr {
+    /// Writes the loop with `body` instead of its own statements.
+    fn write_with_body(&self, body: &str) -> String {
+        let name = &self.variable.lexeme;
+
+        if let Some(value_variable) = &self.value_variable {
+            let (Expression::ArrayRef(ArrayRefExpr { name: collection })
+            | Expression::MapRef(MapRefExpr { name: collection })) = self.iterable.as_ref()
+            else {
+                unreachable!("the parser rejects two loop variables over other iterables");
+            };
+
+            return format!(
+                "for {name} in \"${{!{0}[@]}}\"\ndo\n    {1}=\"${{{0}[${name}]}}\"\n{body}done",
+                collection.lexeme, value_variable.lexeme
+            );
+        }
+
+        match self.iterable.as_ref() {
+            Expression::Range(RangeExpr { start, end }) => format!(
+                "for (({name} = {}; {name} < {}; {name}++))\ndo\n{body}done",
+                start.write_arithmetic(),
+                end.write_arithmetic(),
+            ),
+            Expression::Value(ValueExpr { value }) if value.kind == Kind::Identifier => {
+                format!("for {name} in \"${{{}[@]}}\"\ndo\n{body}done", value.lexeme)
+            }
+            Expression::Array(ArrayExpr { elements }) => {
+                let elements: Vec<String> =
+                    elements.iter().map(Expression::write_element).collect();
+                format!("for {name} in {}\ndo\n{body}done", elements.join(" "))
+            }
+            Expression::MapRef(MapRefExpr { name: map }) => {
+                format!("for {name} in \"${{!{}[@]}}\"\ndo\n{body}done", map.lexeme)
+            }
+            iterable if iterable.is_array() => {
+                format!("for {name} in {}\ndo\n{body}done", iterable.write())
+            }
+            iterable => format!(
+                "while IFS= read -r {name}\ndo\n{body}done < <({})",
+                iterable.write()
+            ),
+        }
+    }
+}
+
+impl Expr for ForStatementExpr {
+    fn write(&self) -> String {
+        if self.body.is_empty() {
+            return String::new();
+        }
+
+        self.write_with_body(&formatter::write_formatted_expressions(&self.body))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionDeclExpr {
+    pub name: Token,
+    pub params: Vec<Token>,
+    pub body: Vec<Expression>,
+    /// Whether the body defers blocks, which run when the function returns.
+    pub defers: bool,
+}
+
+impl Expr for FunctionDeclExpr {
+    fn write(&self) -> String {
+        let mut output = format!("{}() {{\n", self.name.lexeme);
+
+        for (index, param) in self.params.iter().enumerate() {
+            output
+                .write_fmt(format_args!(
+                    "    local {}=\"${}\"\n",
+                    param.lexeme,
+                    index + 1
+                ))
+                .unwrap();
+        }
+
+        // The blocks deferred after the ones already deferred when the
+        // function was called run once it returns
+        if self.defers {
+            output
+                .write_str("    trap \"__run_deferred ${#__deferred[@]}; trap - RETURN\" RETURN\n")
+                .unwrap();
+        }
+
+        if self.params.is_empty() && self.body.is_empty() {
+            output.write_str("    :\n").unwrap();
+        }
+
+        output
+            .write_str(&formatter::write_formatted_expressions(&self.body))
+            .unwrap();
+        output.write_char('}').unwrap();
+
+        output
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReturnExpr {
+    pub value: Option<Box<Expression>>,
+}
+
+/// Variable holding the value returned by the last call to a user function.
+pub const RETURN_VARIABLE: &str = "__ret";
+
+impl Expr for ReturnExpr {
+    fn write(&self) -> String {
+        match &self.value {
+            Some(value) => format!("{RETURN_VARIABLE}={}\nreturn", value.write_element()),
+            None => "return".into(),
+        }
+    }
+}
+
+/// A call to a user function used as a condition, which is true when the
+/// function returns `true`. It is grouped, so that it can be a part of any
+/// list of conditions.
+#[derive(Debug, Clone)]
+pub struct FunctionConditionExpr {
+    pub call: FnCall,
+}
+
+impl Expr for FunctionConditionExpr {
+    fn write(&self) -> String {
+        format!(
+            "{{ {}; [[ \"${RETURN_VARIABLE}\" == true ]]; }}",
+            self.call.write().trim_end()
+        )
+    }
+}
+
+/// An expression using the values returned by calls to user functions. The
+/// functions are called right before it, rather than in command
+/// substitutions, so that they run in the shell of the script and what they
+/// print is not mixed up with their values.
+#[derive(Debug, Clone)]
+pub struct CalledExpr {
+    /// The calls, each with the variable its value is stored in.
+    pub calls: Vec<(Token, FnCall)>,
+    pub expression: Box<Expression>,
+    pub local: bool,
+}
+
+impl Expr for CalledExpr {
+    fn write(&self) -> String {
+        let declare = if self.local { "local " } else { "" };
+        let mut output = String::new();
+
+        for (value, call) in &self.calls {
+            output
+                .write_fmt(format_args!(
+                    "{}\n{declare}{}=\"${RETURN_VARIABLE}\"\n",
+                    call.write().trim_end(),
+                    value.lexeme
+                ))
+                .unwrap();
+        }
+
+        output.write_str(&self.expression.write()).unwrap();
+
+        output
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ArrayExpr {
+    pub elements: Vec<Expression>,
+}
+
+impl Expr for ArrayExpr {
+    fn write(&self) -> String {
+        let elements: Vec<String> = self
+            .elements
+            .iter()
+            .map(Expression::write_element)
+            .collect();
+
+        format!("({})", elements.join(" "))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ArrayRefExpr {
+    pub name: Token,
+}
+
+impl Expr for ArrayRefExpr {
+    fn write(&self) -> String {
+        format!("\"${{{}[@]}}\"", self.name.lexeme)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexExpr {
+    pub name: Token,
+    pub index: Box<Expression>,
+}
+
+impl Expr for IndexExpr {
+    fn write(&self) -> String {
+        format!("\"${{{}[{}]}}\"", self.name.lexeme, self.index.write())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SliceExpr {
+    pub name: Token,
+    pub start: Option<Box<Expression>>,
+    pub end: Option<Box<Expression>>,
+}
+
+impl SliceExpr {
+    /// Writes `bound` as an index from the start of the array, counting a
+    /// negative bound from its end. It is parenthesized unless it is a plain
+    /// number, since `${xs[@]:-1}` would be the default value expansion.
+    fn write_index(&self, bound: &Expression) -> String {
+        let length = format!("${{#{}[@]}}", self.name.lexeme);
+
+        match literal_integer(bound) {
+            Some(index) if index >= 0 => index.to_string(),
+            Some(index) => format!("({length} - {})", index.unsigned_abs()),
+            None => {
+                let index = bound.write_arithmetic();
+                format!("({index} < 0 ? {length} + {index} : {index})")
+            }
+        }
+    }
+}
+
+/// The value of `expression` if it is an integer literal.
+fn literal_integer(expression: &Expression) -> Option<i64> {
+    match expression {
+        Expression::Value(ValueExpr { value }) if value.kind == Kind::Number => {
+            value.lexeme.parse().ok()
+        }
+        _ => None,
+    }
+}
+
+impl Expr for SliceExpr {
+    fn write(&self) -> String {
+        let zero = Expression::Value(ValueExpr {
+            value: Token::new("0", Kind::Number),
+        });
+        let start = self.start.as_deref().unwrap_or(&zero);
+        let offset = self.write_index(start);
+
+        let Some(end) = &self.end else {
+            return match &self.start {
+                Some(_) => format!("\"${{{}[@]:{offset}}}\"", self.name.lexeme),
+                None => format!("\"${{{}[@]}}\"", self.name.lexeme),
+            };
+        };
+
+        // Bash fails on a negative length, which an end before the start gives
+        let length = if let (Some(start @ 0..), Some(end @ 0..)) =
+            (literal_integer(start), literal_integer(end))
+        {
+            (end - start).max(0).to_string()
+        } else {
+            let end = self.write_index(end);
+            format!("({end} - {offset} < 0 ? 0 : {end} - {offset})")
+        };
+
+        format!("\"${{{}[@]:{offset}:{length}}}\"", self.name.lexeme)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexAssignmentExpr {
+    pub name: Token,
+    pub index: Box<Expression>,
+    pub value: Box<Expression>,
+}
+
+impl Expr for IndexAssignmentExpr {
+    fn write(&self) -> String {
+        format!(
+            "{}[{}]={}",
+            self.name.lexeme,
+            self.index.write(),
+            self.value.write_assigned_value()
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LengthExpr {
+    pub value: Box<Expression>,
+}
+
+impl Expr for LengthExpr {
+    fn write(&self) -> String {
+        match self.value.as_ref() {
+            Expression::ArrayRef(ArrayRefExpr { name })
+            | Expression::MapRef(MapRefExpr { name })
+            | Expression::Keys(KeysExpr { name }) => format!("${{#{}[@]}}", name.lexeme),
+            Expression::Array(ArrayExpr { elements }) => elements.len().to_string(),
+            Expression::Value(ValueExpr { value }) if value.kind == Kind::Identifier => {
+                format!("${{#{}}}", value.lexeme)
+            }
+            value => format!("$(printf %s {} | wc -m)", value.write()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PushExpr {
+    pub name: Token,
+    pub values: Vec<Expression>,
+}
+
+impl Expr for PushExpr {
+    fn write(&self) -> String {
+        let values = ArrayExpr {
+            elements: self.values.clone(),
+        };
+
+        format!("{}+={}", self.name.lexeme, values.write())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MapExpr {
+    pub entries: Vec<(Expression, Expression)>,
+}
+
+impl Expr for MapExpr {
+    fn write(&self) -> String {
+        let entries: Vec<String> = self
+            .entries
+            .iter()
+            .map(|(key, value)| format!("[{}]={}", key.write_element(), value.write_element()))
+            .collect();
+
+        format!("({})", entries.join(" "))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MapRefExpr {
+    pub name: Token,
+}
+
+impl Expr for MapRefExpr {
+    fn write(&self) -> String {
+        format!("\"${{{}[@]}}\"", self.name.lexeme)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KeysExpr {
+    pub name: Token,
+}
+
+impl Expr for KeysExpr {
+    fn write(&self) -> String {
+        format!("\"${{!{}[@]}}\"", self.name.lexeme)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HasExpr {
+    pub name: Token,
+    pub key: Box<Expression>,
+}
+
+impl Expr for HasExpr {
+    fn write(&self) -> String {
+        format!(
+            "[[ -v {}[{}] ]]",
+            self.name.lexeme,
+            self.key.write_element()
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LogicalExpr {
+    pub lhs: Box<Expression>,
+    pub operator: Token,
+    pub rhs: Box<Expression>,
+}
+
+impl Expr for LogicalExpr {
+    fn write(&self) -> String {
+        // Shell lists evaluate && and || left to right with equal precedence,
+        // so every subtree that binds differently has to be grouped explicitly.
+        let lhs = match self.lhs.as_ref() {
+            Expression::Logical(lhs)
+                if lhs.operator.kind == Kind::Or && self.operator.kind == Kind::And =>
+            {
+                format!("{{ {}; }}", lhs.write())
+            }
+            lhs if lhs.saves_statuses() => format!("{{ {}; }}", lhs.write()),
+            lhs => lhs.write(),
+        };
+
+        let rhs = match self.rhs.as_ref() {
+            Expression::Logical(rhs) => format!("{{ {}; }}", rhs.write()),
+            rhs if rhs.saves_statuses() => format!("{{ {}; }}", rhs.write()),
+            rhs => rhs.write(),
+        };
+
+        format!("{lhs} {} {rhs}", self.operator.lexeme)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NotExpr {
+    pub value: Box<Expression>,
+}
+
+impl Expr for NotExpr {
+    fn write(&self) -> String {
+        match self.value.as_ref() {
+            Expression::Logical(value) => format!("! {{ {}; }}", value.write()),
+            value if value.saves_statuses() => format!("! {{ {}; }}", value.write()),
+            value => format!("! {}", value.write()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BooleanExpr {
+    pub value: Token,
+}
+
+impl Expr for BooleanExpr {
+    fn write(&self) -> String {
+        match self.value.kind {
+            Kind::Identifier => format!("[[ {} == true ]]", self.value.write()),
+            _ => self.value.lexeme.clone(),
+        }
+    }
+}
+
+/// Placeholder for a statement that could not be parsed, so that the parser can
+/// keep going and report the errors after it.
+#[derive(Debug, Clone)]
+pub struct ErrorExpr {
+    pub span: Span,
+}
+
+impl Expr for ErrorExpr {
+    fn write(&self) -> String {
+        unreachable!("scripts with syntax errors are never written")
+    }
+}
+
+/// A statement with the comments written on the lines before it.
+#[derive(Debug, Clone)]
+pub struct CommentedExpr {
+    pub comments: Vec<Token>,
+    pub statement: Option<Box<Expression>>,
+}
+
+impl Expr for CommentedExpr {
+    fn write(&self) -> String {
+        let mut lines: Vec<String> = self
+            .comments
+            .iter()
+            .map(|comment| format!("#{}", comment.lexeme))
+            .collect();
+
+        if let Some(statement) = &self.statement {
+            lines.push(statement.write());
+        }
+
+        lines.join("\n")
+    }
+}
+
+/// A string with expressions interpolated into it. Its parts are either the
+/// text between the expressions, as string values, or the expressions.
+#[derive(Debug, Clone)]
+pub struct InterpolatedStringExpr {
+    pub parts: Vec<Expression>,
+}
+
+impl Expr for InterpolatedStringExpr {
+    fn write(&self) -> String {
+        let mut output = String::from("\"");
+
+        for part in &self.parts {
+            match part {
+                Expression::Value(ValueExpr { value }) => match value.kind {
+                    Kind::Identifier => write!(output, "${{{}}}", value.lexeme).unwrap(),
+                    Kind::Status => output.push_str("$?"),
+                    _ => output.push_str(&quoting::escape_double_quoted(&value.lexeme)),
+                },
+                Expression::ArrayRef(ArrayRefExpr { name }) => {
+                    write!(output, "${{{}[*]}}", name.lexeme).unwrap();
+                }
+                Expression::Arithmetic(expr) if expr.float => {
+                    write!(output, "$({})", expr.write_awk()).unwrap();
+                }
+                Expression::Unary(expr) if expr.float => {
+                    write!(output, "$({})", expr.write_awk()).unwrap();
+                }
+                Expression::Arithmetic(_) | Expression::Unary(_) => {
+                    output.push_str(&part.write());
+                }
+                Expression::FnCall(_) | Expression::FnChain(_) => {
+                    write!(output, "$({})", part.write()).unwrap();
+                }
+                Expression::InterpolatedString(_) => {
+                    let string = part.write();
+                    output.push_str(&string[1..string.len() - 1]);
+                }
+                // Other expressions are written as words of their own, which
+                // are joined to the string by closing and reopening the quotes
+                _ => write!(output, "\"{}\"", part.write()).unwrap(),
+            }
+        }
+
+        output.push('"');
+        output
+    }
+}
+
+/// A command whose exit status and output are stored in a map with the keys
+/// `code`, `stdout` and `stderr`. The output is collected in temporary files,
+/// since a command substitution can only capture one stream.
+#[derive(Debug, Clone)]
+pub struct RunExpr {
+    pub command: Box<Expression>,
+    pub id: usize,
+}
+
+impl RunExpr {
+    fn write_declaration(&self, name: &str, local: bool) -> String {
+        let code = format!("__run{}", self.id);
+        let stdout = format!("{code}_out");
+        let stderr = format!("{code}_err");
+        let declare = if local { "local -A" } else { "declare -A" };
+
+        // The streams of every command of a pipeline are redirected together
+        let command = match self.command.as_ref() {
+            Expression::FnChain(chain)
+                if chain.invocations.len() > 1 || !chain.redirects.is_empty() =>
+            {
+                format!("{{ {}; }}", chain.write())
+            }
+            command => command.write().trim_end().to_string(),
+        };
+
+        // `&& ||` keeps a failing command from stopping a script with set -e
+        [
+            format!("{stdout}=\"$(mktemp)\""),
+            format!("{stderr}=\"$(mktemp)\""),
+            format!("{command} >\"${stdout}\" 2>\"${stderr}\" && {code}=0 || {code}=$?"),
+            format!(
+                "{declare} {name}=([code]=\"${code}\" [stdout]=\"$(< \"${stdout}\")\" [stderr]=\"$(< \"${stderr}\")\")"
+            ),
+            format!("rm -f \"${stdout}\" \"${stderr}\""),
+        ]
+        .join("\n")
+    }
+}
+
+/// A text processing step of a chain, working on the lines of the output
+/// of the command before it.
+#[derive(Debug, Clone)]
+pub enum ChainBuiltin {
+    /// Keeps the lines matching an extended regular expression.
+    Filter(Box<Expression>),
+    /// Replaces every line, bound to `parameter`, with `body`.
+    Map {
+        parameter: Token,
+        body: Box<Expression>,
+    },
+    Take(Box<Expression>),
+    Skip(Box<Expression>),
+    Sort,
+    Uniq,
+    Count,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChainBuiltinExpr {
+    pub name: Token,
+    pub builtin: ChainBuiltin,
+}
+
+impl Expr for ChainBuiltinExpr {
+    fn write(&self) -> String {
+        match &self.builtin {
+            // grep fails when no line matches, which is not an error here
+            ChainBuiltin::Filter(pattern) => format!(
+                "{{ grep -E -- {} || (( $? == 1 )); }}",
+                pattern.write_element()
+            ),
+            ChainBuiltin::Map { parameter, body } => format!(
+                "while IFS= read -r {}; do printf '%s\\n' {}; done",
+                parameter.lexeme,
+                body.write_element()
+            ),
+            // Unlike head, awk reads all of its input, so that the commands
+            // before it are not killed by SIGPIPE and fail the pipeline
+            ChainBuiltin::Take(count) => {
+                format!("awk -v n={} 'NR <= n'", count.write_element())
+            }
+            ChainBuiltin::Skip(count) => format!("awk -v n={} 'NR > n'", count.write_element()),
+            ChainBuiltin::Sort => "sort".into(),
+            ChainBuiltin::Uniq => "uniq".into(),
+            ChainBuiltin::Count => "wc -l".into(),
+        }
+    }
+}
+
+/// A command or block run in the background. Its process id is stored in
+/// the variable it is assigned to, to be waited for with `wait()`.
+#[derive(Debug, Clone)]
+pub struct SpawnExpr {
+    pub body: Vec<Expression>,
+}
+
+impl SpawnExpr {
+    fn write_declaration(&self, name: &str, local: bool) -> String {
+        let declare = if local { "local " } else { "" };
+
+        format!("{}\n{declare}{name}=$!", self.write())
+    }
+}
+
+impl Expr for SpawnExpr {
+    fn write(&self) -> String {
+        match self.body.as_slice() {
+            [command @ (Expression::FnCall(_) | Expression::FnChain(_))] => {
+                format!("{} &", command.write().trim_end())
+            }
+            body => format!("{{\n{}}} &", write_loop_body(body)),
+        }
+    }
+}
+
+/// A for loop running each iteration as a background job, with at most
+/// `max` of them at a time. The process IDs of the jobs still running are the
+/// keys of the map `jobs`, so that only the jobs of the loop are waited for.
+/// The status of the last failing job is the status of the loop.
+#[derive(Debug, Clone)]
+pub struct ParallelExpr {
+    pub max: Box<Expression>,
+    pub each: ForStatementExpr,
+    pub jobs: Token,
+}
+
+impl Expr for ParallelExpr {
+    fn write(&self) -> String {
+        let jobs = &self.jobs.lexeme;
+        let job = SpawnExpr {
+            body: self.each.body.clone(),
+        };
+
+        let throttled = format!(
+            "__wait_jobs {jobs} {jobs}_status \"$(({}))\"\n{}\n{jobs}[$!]=1",
+            self.max.write_arithmetic(),
+            job.write()
+        );
+
+        let mut body = String::new();
+        for line in throttled.split('\n') {
+            writeln!(body, "    {line}").unwrap();
+        }
+
+        format!(
+            "declare -A {jobs}=()\n{jobs}_status=0\n{}\n__wait_jobs {jobs} {jobs}_status 1\n(exit \"${jobs}_status\")",
+            self.each.write_with_body(&body)
+        )
+    }
+}
+
+/// Helper waiting until fewer than `$3` of the jobs in the map named `$1` are
+/// running, storing the status of the ones that failed in the variable named
+/// `$2`. `wait -n` only waits for a job to end, since it cannot tell the
+/// status of jobs that bash has already cleaned up, which `wait` still can.
+pub const PARALLEL_RUNTIME: &str = r#"__wait_jobs() {
+    local -n __running=$1 __failed=$2
+    local __pid
+    while true
+    do
+        for __pid in "${!__running[@]}"
+        do
+            if ! kill -0 "$__pid" 2>/dev/null; then
+                wait "$__pid" || __failed=$?
+                unset '__running[$__pid]'
+            fi
+        done
+        (( ${#__running[@]} >= $3 )) || return 0
+        wait -n "${!__running[@]}" 2>/dev/null || true
+    done
+}
+"#;
+
+/// Helper running the deferred blocks after the first `$1` of them, the last
+/// one first, and the trap running all of them when the script exits. A
+/// failing block does not keep the ones before it from running, and the
+/// functions called by the blocks do not change the value being returned.
+pub const DEFER_RUNTIME: &str = r#"__deferred=()
+__run_deferred() {
+    local __ret="$__ret"
+    while (( ${#__deferred[@]} > $1 ))
+    do
+        local __defer="${__deferred[-1]}"
+        unset '__deferred[-1]'
+        "$__defer" || true
+    done
+}
+trap '__run_deferred 0' EXIT
+"#;
+
+/// A block that runs when the function around it returns, or when the script
+/// exits. It is written as a function registered in `__deferred`, so that
+/// only the blocks whose `defer` was reached run.
+#[derive(Debug, Clone)]
+pub struct DeferExpr {
+    pub body: Vec<Expression>,
+    pub id: usize,
+}
+
+impl Expr for DeferExpr {
+    fn write(&self) -> String {
+        let name = format!("__defer{}", self.id);
+
+        format!(
+            "{name}() {{\n{}}}\n__deferred+=({name})",
+            write_loop_body(&self.body)
+        )
+    }
+}
@@ -1,17 +1,24 @@
 use std::fmt::Write;
 
-use crate::expression::Expression;
+use crate::{expression::Expression, quoting};
 
+/// Surrounds the bash word `str` with the escape codes of `color`, keeping it one word.
 pub fn colorize_string(color: &str, str: &str) -> String {
-    match color {
-        "red" => format!(r#"\u001b[31m{str}\u001b[0m"#),
-        "green" => format!(r#"\u001b[32m{str}\u001b[0m"#),
-        "yellow" => format!(r#"\u001b[33m{str}\u001b[0m"#),
-        "blue" => format!(r#"\u001b[34m{str}\u001b[0m"#),
-        "magenta" => format!(r#"\u001b[35m{str}\u001b[0m"#),
-        "cyan" => format!(r#"\u001b[36m{str}\u001b[0m"#),
-        _ => str.into(),
-    }
+    let code = match color {
+        "red" => 31,
+        "green" => 32,
+        "yellow" => 33,
+        "blue" => 34,
+        "magenta" => 35,
+        "cyan" => 36,
+        _ => return str.into(),
+    };
+
+    format!(
+        "{}{str}{}",
+        quoting::ansi_c(&format!("\x1b[{code}m")),
+        quoting::ansi_c("\x1b[0m")
+    )
 }
 
 pub fn get_args_as_string(args: &[Expression]) -> String {
@@ -23,11 +30,10 @@ pub fn get_args_as_string(args: &[Expression]) -> String {
         arguments_string
             .write_str(&match arg.clone() {
                 Expression::FnCall(fn_call) => match fn_call.name.lexeme.as_str() {
-                    "red" | "green" | "yellow" | "blue" | "magenta" | "cyan" => {
-                        format!("\"{}\"", arg.write())
-                    }
-                    _ => format!("\"$({})\"", arg.write()),
+                    "red" | "green" | "yellow" | "blue" | "magenta" | "cyan" => arg.write(),
+                    _ => quoting::command_substitution(&arg.write()),
                 },
+                Expression::FnChain(_) => quoting::command_substitution(&arg.write()),
                 _ => arg.write(),
             })
             .unwrap();
@@ -55,5 +61,10 @@ pub fn write_formatted_expressions(expressions: &[Expression]) -> String {
         output.write_str(&lines).unwrap();
     }
 
+    // A block of only comments would be empty for bash
+    if !expressions.is_empty() && expressions.iter().all(Expression::is_comment) {
+        output.write_str("    :\n").unwrap();
+    }
+
     output
 }
@@ -1,10 +1,24 @@
-use crate::token::{Kind, Token};
+use crate::{
+    diagnostic::Diagnostic,
+    token::{Kind, Span, Token},
+};
 
 pub struct Lexer {
     index: usize,
+    byte_index: usize,
     chars: Vec<char>,
     line_counter: usize,
     column_counter: usize,
+    diagnostics: Vec<Diagnostic>,
+    /// For every string whose interpolated expression is being lexed, the
+    /// number of braces opened inside of the expression and the span of the
+    /// quote that starts the string.
+    interpolations: Vec<(usize, Span)>,
+    /// Brackets that are open, from the outermost. New lines inside of
+    /// parentheses and square brackets never end a statement.
+    groups: Vec<Group>,
+    /// Kind of the last token, to tell whether a new line ends a statement.
+    previous: Option<Kind>,
 }
 
 #[allow(clippy::unnecessary_wraps)]
@@ -12,34 +26,150 @@ impl Lexer {
     pub fn from_string(input: &str) -> Self {
         Lexer {
             index: 0,
+            byte_index: 0,
             line_counter: 1,
             column_counter: 1,
             chars: input.chars().collect(),
+            diagnostics: Vec::new(),
+            interpolations: Vec::new(),
+            groups: Vec::new(),
+            previous: None,
         }
     }
 
-    pub fn parse(&mut self) -> Vec<Token> {
+    /// Splits the input into tokens, reporting every character that does not
+    /// start a valid token.
+    ///
+    /// # Errors
+    ///
+    /// Returns the diagnostics of all invalid characters found in the input.
+    pub fn parse(&mut self) -> Result<Vec<Token>, Vec<Diagnostic>> {
         let mut tokens = Vec::new();
 
         while self.index < self.chars.len() {
+            let index = self.index;
+            let span = self.span_from(self.byte_index);
+
             match self.seek_token() {
-                Some(token) => tokens.push(token),
-                None => self.skip_char(),
+                Some(mut token) => {
+                    token.span = Span {
+                        end: self.byte_index,
+                        ..span
+                    };
+
+                    self.track_group(token.kind);
+
+                    self.previous = Some(token.kind);
+                    tokens.push(token);
+                }
+                None if self.index == index => self.skip_char(),
+                None => {}
             }
         }
 
-        tokens
+        while let Some((_, quote)) = self.interpolations.pop() {
+            self.unterminated_string(quote, "add a } to end the interpolated expression");
+        }
+
+        if self.diagnostics.is_empty() {
+            Ok(tokens)
+        } else {
+            Err(std::mem::take(&mut self.diagnostics))
+        }
+    }
+
+    fn track_group(&mut self, kind: Kind) {
+        match kind {
+            Kind::LeftParen if self.previous == Some(Kind::Command) => {
+                self.groups.push(Group::Arguments);
+            }
+            Kind::LeftParen => self.groups.push(Group::Parentheses),
+            Kind::LeftBracket => self.groups.push(Group::Brackets),
+            Kind::LeftBrace => {
+                // A block cannot be inside of parentheses, so the ones before
+                // it were left open by mistake. A map can be, like in `f({})`.
+                let opens_map = matches!(
+                    self.previous,
+                    Some(
+                        Kind::Equal
+                            | Kind::LeftParen
+                            | Kind::LeftBracket
+                            | Kind::Coma
+                            | Kind::Colon
+                            | Kind::Return
+                    )
+                );
+
+                if opens_map {
+                    self.groups.push(Group::Map);
+                } else {
+                    while self
+                        .groups
+                        .last()
+                        .is_some_and(|group| group.continues_line())
+                    {
+                        self.groups.pop();
+                    }
+
+                    self.groups.push(Group::Block);
+                }
+            }
+            Kind::RightParen
+                if matches!(
+                    self.groups.last(),
+                    Some(Group::Parentheses | Group::Arguments)
+                ) =>
+            {
+                self.groups.pop();
+            }
+            Kind::RightBracket if self.groups.last() == Some(&Group::Brackets) => {
+                self.groups.pop();
+            }
+            // Also closes the brackets left open inside of the block
+            Kind::RightBrace => {
+                while let Some(group) = self.groups.pop() {
+                    if matches!(group, Group::Map | Group::Block) {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn span_from(&self, start: usize) -> Span {
+        Span {
+            start,
+            end: self.byte_index,
+            line: self.line_counter,
+            column: self.column_counter,
+        }
     }
 
     fn seek_token(&mut self) -> Option<Token> {
         match self.get_char().unwrap() {
             '#' => self.match_comment(),
             '"' => self.match_string(),
+            '{' => {
+                if let Some((depth, _)) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.match_char_token()
+            }
+            '}' => match self.interpolations.last_mut() {
+                Some((0, _)) => self.match_string_continuation(),
+                Some((depth, _)) => {
+                    *depth -= 1;
+                    self.match_char_token()
+                }
+                None => self.match_char_token(),
+            },
             '`' => self.match_raw_string(),
+            '/' => self.match_slash(),
             '-' => self.match_option(),
             '0'..='9' => self.match_number(),
             'a'..='z' | 'A'..='Z' => self.match_identifier(),
-            ' ' | '\t' => self.match_whitespace(),
+            ' ' | '\t' | '\r' => self.match_whitespace(),
             '\n' => self.match_new_line(),
             _ => self.match_char_token(),
         }
@@ -50,6 +180,9 @@ impl Lexer {
     }
 
     fn skip_char(&mut self) {
+        if let Some(char) = self.get_char() {
+            self.byte_index += char.len_utf8();
+        }
         self.index += 1;
         self.column_counter += 1;
     }
@@ -103,71 +236,125 @@ impl Lexer {
         match self.consume_any_char()? {
             '=' => {
                 if self.consume_char('=').is_some() {
-                    Some(Token {
-                        lexeme: "==".into(),
-                        kind: Kind::EqualEqual,
-                    })
+                    Some(Token::new("==", Kind::EqualEqual))
                 } else {
-                    Some(Token {
-                        lexeme: '='.into(),
-                        kind: Kind::Equal,
-                    })
+                    Some(Token::new('=', Kind::Equal))
                 }
             }
             '<' => {
                 if self.consume_char('=').is_some() {
-                    Some(Token {
-                        lexeme: "<=".into(),
-                        kind: Kind::LessEqual,
-                    })
+                    Some(Token::new("<=", Kind::LessEqual))
+                } else if self.consume_char('<').is_some() {
+                    Some(Token::new("<<", Kind::ShiftLeft))
                 } else {
-                    Some(Token {
-                        lexeme: '<'.into(),
-                        kind: Kind::Less,
-                    })
+                    Some(Token::new('<', Kind::Less))
                 }
             }
             '>' => {
                 if self.consume_char('=').is_some() {
-                    Some(Token {
-                        lexeme: ">=".into(),
-                        kind: Kind::GreaterEqual,
-                    })
+                    Some(Token::new(">=", Kind::GreaterEqual))
+                } else if self.consume_char('>').is_some() {
+                    Some(Token::new(">>", Kind::ShiftRight))
                 } else {
-                    Some(Token {
-                        lexeme: '>'.into(),
-                        kind: Kind::Greater,
-                    })
+                    Some(Token::new('>', Kind::Greater))
                 }
             }
             '!' => {
                 if self.consume_char('=').is_some() {
-                    Some(Token {
-                        lexeme: "!=".into(),
-                        kind: Kind::NotEqual,
-                    })
+                    Some(Token::new("!=", Kind::NotEqual))
                 } else {
-                    Some(Token {
-                        lexeme: '!'.into(),
-                        kind: Kind::Not,
-                    })
-                }
-            }
-            char => match Token::from_char(char) {
-                Some(token) => Some(token),
-                None => panic!(
-                    "Syntax error: Unknown character '{}' at column {}, line {}",
-                    char, self.column_counter, self.line_counter
-                ),
-            },
+                    Some(Token::new('!', Kind::Not))
+                }
+            }
+            '&' => {
+                if self.consume_char('&').is_some() {
+                    Some(Token::new("&&", Kind::And))
+                } else {
+                    Some(Token::new('&', Kind::Ampersand))
+                }
+            }
+            '|' => {
+                if self.consume_char('|').is_some() {
+                    Some(Token::new("||", Kind::Or))
+                } else if self.consume_char('&').is_some() {
+                    Some(Token::new("|&", Kind::PipeAmpersand))
+                } else {
+                    Some(Token::new('|', Kind::Pipe))
+                }
+            }
+            '*' => {
+                if self.consume_char('*').is_some() {
+                    Some(Token::new("**", Kind::Power))
+                } else {
+                    Some(Token::new('*', Kind::Asterisk))
+                }
+            }
+            '.' => {
+                if self.consume_char('.').is_some() {
+                    Some(Token::new("..", Kind::Range))
+                } else {
+                    Some(Token::new('.', Kind::Period))
+                }
+            }
+            char => {
+                let token = Token::from_char(char);
+
+                if token.is_none() {
+                    let span = Span {
+                        start: self.byte_index - char.len_utf8(),
+                        column: self.column_counter - 1,
+                        ..self.span_from(self.byte_index)
+                    };
+
+                    self.diagnostics.push(
+                        Diagnostic::error(format!("Unknown character '{char}'"))
+                            .with_label(span, "not valid in sash source"),
+                    );
+                }
+
+                token
+            }
         }
     }
 
+    /// Matches the end of a line, which ends the statement on it unless the
+    /// expression goes on: inside of parentheses or brackets, after an
+    /// operator, or before a `.` continuing a chain on the next line.
+    /// Empty lines do not make tokens of their own.
     fn match_new_line(&mut self) -> Option<Token> {
+        self.consume_char('\n');
+        self.new_line();
+
+        let continues = self
+            .groups
+            .last()
+            .is_some_and(|group| group.continues_line())
+            || !self.interpolations.is_empty()
+            || self
+                .previous
+                .is_none_or(|kind| kind.is_separator() || kind.continues_line())
+            || self.next_line_continues_chain();
+
+        if continues {
+            None
+        } else {
+            Some(Token::new('\n', Kind::Newline))
+        }
+    }
+
+    fn new_line(&mut self) {
         self.line_counter += 1;
-        self.column_counter = 0;
+        self.column_counter = 1;
+    }
 
-        None
+    /// Whether the next line that is not empty starts with a `.` calling
+    /// another function of a chain.
+    fn next_line_continues_chain(&self) -> bool {
+        let mut chars = self.chars[self.index..]
+            .iter()
+            .skip_while(|char| char.is_whitespace());
+
+        chars.next() == Some(&'.') && chars.next() != Some(&'.')
     }
 
     #[allow(clippy::unused_self)]
@@ -175,15 +362,24 @@ impl Lexer {
         None
     }
 
+    /// Matches a comment, which is dropped when it is inside of an expression
+    /// that goes on over several lines, since it has no statement to go with.
     fn match_comment(&mut self) -> Option<Token> {
         self.consume_any_char();
 
         let lexeme = self.consume_lexeme_until(|char| *char != '\n').unwrap();
 
-        Some(Token {
-            lexeme,
-            kind: Kind::Comment,
-        })
+        let inside_expression = self
+            .groups
+            .last()
+            .is_some_and(|group| group.continues_line() || *group == Group::Map)
+            || self.previous.is_some_and(Kind::continues_line);
+
+        if inside_expression {
+            None
+        } else {
+            Some(Token::new(lexeme, Kind::Comment))
+        }
     }
 
     fn match_identifier(&mut self) -> Option<Token> {
@@ -191,24 +387,116 @@ impl Lexer {
             .consume_lexeme_until(|char| char.is_alphanumeric() || *char == '_')
             .unwrap();
 
-        Token::from_keyword(&lexeme).or(Some(Token {
-            lexeme,
-            kind: match self.consume_char('!') {
-                Some(_) => Kind::Command,
-                None => Kind::Identifier,
-            },
-        }))
+        // `name!` invokes a command, even when its name is a keyword like `true!`,
+        // while `name!=` is the start of a comparison
+        if self.match_char('!').is_some() && self.chars.get(self.index + 1) != Some(&'=') {
+            self.consume_char('!');
+            return Some(Token::new(lexeme, Kind::Command));
+        }
+
+        Token::from_keyword(&lexeme).or(Some(Token::new(lexeme, Kind::Identifier)))
     }
 
+    /// Matches a string literal up to its end, or up to the `{` of its first
+    /// interpolated expression.
     fn match_string(&mut self) -> Option<Token> {
-        let lexeme = self
-            .consume_lexeme_delimited('"')
-            .expect("Cannot parse raw string");
+        let quote = self.span_from(self.byte_index);
+        self.consume_char('"');
+
+        self.match_string_part(quote, Kind::String, Kind::InterpolationStart)
+    }
+
+    /// Matches the rest of a string after the `}` of an interpolated expression.
+    fn match_string_continuation(&mut self) -> Option<Token> {
+        self.consume_char('}');
+        let (_, quote) = self.interpolations.pop()?;
+
+        self.match_string_part(quote, Kind::InterpolationEnd, Kind::InterpolationMiddle)
+    }
+
+    /// Matches the text of a string, decoding its escape sequences, until the
+    /// closing quote, which makes a token of kind `end`, or until a `{`,
+    /// which makes a token of kind `interpolation` and starts lexing the
+    /// interpolated expression.
+    fn match_string_part(&mut self, quote: Span, end: Kind, interpolation: Kind) -> Option<Token> {
+        let mut lexeme = String::new();
+
+        loop {
+            let escape = self.span_from(self.byte_index);
+
+            match self.consume_any_char() {
+                Some('"') => return Some(Token::new(lexeme, end)),
+                Some('{') => {
+                    self.interpolations.push((0, quote));
+                    return Some(Token::new(lexeme, interpolation));
+                }
+                Some('\\') => lexeme.extend(self.match_escape(escape)),
+                Some('\n') => {
+                    self.new_line();
+                    lexeme.push('\n');
+                }
+                Some(char) => lexeme.push(char),
+                None => {
+                    self.unterminated_string(quote, "add a \" to end the string");
+                    return None;
+                }
+            }
+        }
+    }
 
-        Some(Token {
-            lexeme,
-            kind: Kind::String,
-        })
+    fn unterminated_string(&mut self, quote: Span, note: &str) {
+        self.diagnostics.push(
+            Diagnostic::error("Unterminated string")
+                .with_label(
+                    Span {
+                        end: quote.start + 1,
+                        ..quote
+                    },
+                    "string starts here",
+                )
+                .with_note(note),
+        );
+    }
+
+    /// Decodes the escape sequence after a `\` at `escape`.
+    fn match_escape(&mut self, escape: Span) -> Option<char> {
+        let char = match self.consume_any_char() {
+            Some('n') => Some('\n'),
+            Some('t') => Some('\t'),
+            Some(char @ ('"' | '\\' | '$' | '{' | '}')) => Some(char),
+            Some('u') => self.match_unicode_escape(),
+            _ => None,
+        };
+
+        if char.is_none() {
+            let span = Span {
+                end: self.byte_index,
+                ..escape
+            };
+
+            self.diagnostics.push(
+                Diagnostic::error("Invalid escape sequence")
+                    .with_label(span, "not a valid escape")
+                    .with_note(r#"the valid escapes are \", \\, \n, \t, \$, \{, \} and \u{..}"#),
+            );
+        }
+
+        char
+    }
+
+    /// Decodes the `{..}` of a `\u{..}` escape, with 1 to 6 hexadecimal digits.
+    fn match_unicode_escape(&mut self) -> Option<char> {
+        self.consume_char('{')?;
+
+        let digits = self.consume_lexeme_until(char::is_ascii_hexdigit)?;
+
+        self.consume_char('}')?;
+
+        if digits.is_empty() || digits.len() > 6 {
+            return None;
+        }
+
+        char::from_u32(u32::from_str_radix(&digits, 16).ok()?)
     }
 
     fn match_raw_string(&mut self) -> Option<Token> {
@@ -216,38 +504,297 @@ impl Lexer {
             .consume_lexeme_delimited('`')
             .expect("Cannot parse raw string");
 
-        Some(Token {
-            lexeme,
-            kind: Kind::RawString,
-        })
+        Some(Token::new(lexeme, Kind::RawString))
     }
 
+    /// Matches a division, or a regular expression where no value comes
+    /// before the slash to divide, like in `.filter(/^a+$/)`. A `/` in the
+    /// expression is escaped as `\/`.
+    fn match_slash(&mut self) -> Option<Token> {
+        if !matches!(
+            self.previous,
+            Some(Kind::LeftParen | Kind::LeftBracket | Kind::Coma | Kind::Equal | Kind::Arrow)
+        ) {
+            self.consume_char('/');
+            return Some(Token::new('/', Kind::Slash));
+        }
+
+        let start = self.span_from(self.byte_index);
+        self.consume_char('/');
+        let mut lexeme = String::new();
+
+        loop {
+            match self.get_char().copied() {
+                Some('/') => {
+                    self.consume_char('/');
+                    return Some(Token::new(lexeme, Kind::Regex));
+                }
+                Some('\\') if self.chars.get(self.index + 1) == Some(&'/') => {
+                    self.consume_char('\\');
+                    self.consume_char('/');
+                    lexeme.push('/');
+                }
+                Some('\n') | None => {
+                    self.diagnostics.push(
+                        Diagnostic::error("Unterminated regular expression")
+                            .with_label(
+                                Span {
+                                    end: start.start + 1,
+                                    ..start
+                                },
+                                "regular expression starts here",
+                            )
+                            .with_note(
+                                "add a / at the end, or write \\/ for a / in the expression",
+                            ),
+                    );
+                    return None;
+                }
+                Some(char) => {
+                    self.consume_char(char);
+                    lexeme.push(char);
+                }
+            }
+        }
+    }
+
+    /// Matches an integer, in decimal or with a `0x`, `0b` or `0o` prefix, or
+    /// a decimal float. Digits can be separated with underscores. Integers
+    /// are written back in decimal, the only base that all of bash understands.
     fn match_number(&mut self) -> Option<Token> {
-        let lexeme = self
-            .consume_lexeme_until(|char| char.is_alphanumeric())
+        let start = self.span_from(self.byte_index);
+
+        let mut lexeme = self
+            .consume_lexeme_until(|char| char.is_alphanumeric() || *char == '_')
             .unwrap();
 
-        Some(Token {
-            lexeme,
-            kind: Kind::Number,
-        })
+        // A fraction, unless the dot starts a range like `0..10`
+        if self.match_char('.').is_some()
+            && self
+                .chars
+                .get(self.index + 1)
+                .is_some_and(char::is_ascii_digit)
+        {
+            self.consume_char('.');
+            lexeme.push('.');
+            lexeme.extend(self.consume_lexeme_until(|char| char.is_alphanumeric() || *char == '_'));
+        }
+
+        match parse_number(&lexeme) {
+            Ok(token) => Some(token),
+            Err(message) => {
+                let span = Span {
+                    end: self.byte_index,
+                    ..start
+                };
+
+                self.diagnostics
+                    .push(Diagnostic::error(message).with_label(span, "invalid number"));
+                None
+            }
+        }
     }
 
     fn match_option(&mut self) -> Option<Token> {
+        if self.chars.get(self.index + 1) == Some(&'>') {
+            self.consume_char('-');
+            self.consume_char('>');
+            return Some(Token::new("->", Kind::Arrow));
+        }
+
+        // A negative number, which is parsed as a minus before the number
+        if self
+            .chars
+            .get(self.index + 1)
+            .is_some_and(char::is_ascii_digit)
+        {
+            self.consume_char('-');
+            return Some(Token::new('-', Kind::Minus));
+        }
+
+        // A negated variable where a value is expected, like in `x = -a`.
+        // Arguments of commands are still options, like in `ls!(-a)`.
+        let negates = match self.previous {
+            Some(Kind::LeftParen | Kind::Coma) => self.groups.last() != Some(&Group::Arguments),
+            Some(Kind::Period | Kind::PipeAmpersand) | None => false,
+            Some(kind) => kind.continues_line() || matches!(kind, Kind::LeftBracket | Kind::Return),
+        };
+
+        if negates
+            && self
+                .chars
+                .get(self.index + 1)
+                .is_some_and(|char| char.is_alphabetic() || *char == '_')
+        {
+            self.consume_char('-');
+            return Some(Token::new('-', Kind::Minus));
+        }
+
         let lexeme = self
             .consume_lexeme_until(|char| char.is_alphanumeric() || *char == '-')
             .expect("Cannot parse option lexeme");
 
         if lexeme == "-" {
-            Some(Token {
-                lexeme: '-'.into(),
-                kind: Kind::Minus,
-            })
+            Some(Token::new('-', Kind::Minus))
         } else {
-            Some(Token {
-                lexeme,
-                kind: Kind::Option,
-            })
+            Some(Token::new(lexeme, Kind::Option))
         }
     }
 }
+
+/// A bracket that is open while lexing.
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Group {
+    Parentheses,
+    /// Parentheses around the arguments of a command.
+    Arguments,
+    Brackets,
+    Map,
+    Block,
+}
+
+impl Group {
+    /// Whether a new line inside of the group goes on with the expression.
+    fn continues_line(self) -> bool {
+        matches!(self, Self::Parentheses | Self::Arguments | Self::Brackets)
+    }
+}
+
+fn parse_number(lexeme: &str) -> Result<Token, String> {
+    let (radix, digits) = match lexeme.get(..2) {
+        Some("0x") => (16, &lexeme[2..]),
+        Some("0o") => (8, &lexeme[2..]),
+        Some("0b") => (2, &lexeme[2..]),
+        _ => (10, lexeme),
+    };
+
+    if digits.is_empty() {
+        return Err(format!("Expected digits after {lexeme}"));
+    }
+
+    if digits.ends_with('_')
+        || digits.starts_with('_')
+        || digits.contains("_.")
+        || digits.contains("._")
+    {
+        return Err("Underscores can only separate digits".into());
+    }
+
+    let digits = digits.replace('_', "");
+
+    if let Some(invalid) = digits
+        .chars()
+        .find(|char| !(char.is_digit(radix) || (*char == '.' && radix == 10)))
+    {
+        return Err(format!(
+            "Invalid digit '{invalid}' in a base {radix} number"
+        ));
+    }
+
+    if digits.contains('.') {
+        return Ok(Token::new(digits, Kind::Float));
+    }
+
+    match i64::from_str_radix(&digits, radix) {
+        Ok(value) => Ok(Token::new(value.to_string(), Kind::Number)),
+        Err(_) => Err(format!(
+            "Number is too large, integers can be at most {}",
+            i64::MAX
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<Kind> {
+        Lexer::from_string(source)
+            .parse()
+            .unwrap()
+            .into_iter()
+            .map(|token| token.kind)
+            .collect()
+    }
+
+    #[test]
+    fn minus_before_a_value_and_options_of_calls() {
+        assert_eq!(
+            kinds("x = -a"),
+            [Kind::Identifier, Kind::Equal, Kind::Minus, Kind::Identifier]
+        );
+        assert_eq!(
+            kinds("0 - -a"),
+            [Kind::Number, Kind::Minus, Kind::Minus, Kind::Identifier]
+        );
+        assert_eq!(
+            kinds("ls!(-la, x)"),
+            [
+                Kind::Command,
+                Kind::LeftParen,
+                Kind::Option,
+                Kind::Coma,
+                Kind::Identifier,
+                Kind::RightParen
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_inside_of_expressions_are_dropped() {
+        assert!(kinds("# kept\na").contains(&Kind::Comment));
+        assert!(!kinds("let m = {\n# dropped\n\"a\": 1\n}").contains(&Kind::Comment));
+        assert!(!kinds("f(1, # dropped\n2)").contains(&Kind::Comment));
+        assert!(!kinds("let a = 1 + # dropped\n2").contains(&Kind::Comment));
+        assert!(kinds("if (a) {\n# kept\n}").contains(&Kind::Comment));
+    }
+
+    fn lexemes(source: &str) -> Vec<String> {
+        Lexer::from_string(source)
+            .parse()
+            .unwrap()
+            .into_iter()
+            .map(|token| token.lexeme)
+            .collect()
+    }
+
+    #[test]
+    fn numbers_are_written_in_decimal() {
+        assert_eq!(lexemes("0xff 0b101 0o17 1_000"), ["255", "5", "15", "1000"]);
+        assert_eq!(
+            kinds("1.5 0..10"),
+            [Kind::Float, Kind::Number, Kind::Range, Kind::Number]
+        );
+    }
+
+    #[test]
+    fn invalid_numbers_are_reported() {
+        assert!(Lexer::from_string("0x").parse().is_err());
+        assert!(Lexer::from_string("1__").parse().is_err());
+        assert!(Lexer::from_string("0b102").parse().is_err());
+    }
+
+    #[test]
+    fn new_lines_inside_of_brackets_do_not_end_statements() {
+        assert!(!kinds("f(1,\n2)").contains(&Kind::Newline));
+        assert!(!kinds("[1,\n2]").contains(&Kind::Newline));
+        assert_eq!(
+            kinds("a\nb")
+                .iter()
+                .filter(|kind| **kind == Kind::Newline)
+                .count(),
+            1
+        );
+        assert!(!kinds("a +\nb").contains(&Kind::Newline));
+        assert!(!kinds("ls!()\n    .sort()").contains(&Kind::Newline));
+    }
+
+    #[test]
+    fn slashes_are_regexes_where_no_value_comes_before_them() {
+        assert_eq!(
+            kinds("a / b"),
+            [Kind::Identifier, Kind::Slash, Kind::Identifier]
+        );
+        assert_eq!(lexemes("f(/a\\/b/)")[2], "a/b");
+    }
+}
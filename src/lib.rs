@@ -1,56 +1,276 @@
 #![warn(clippy::pedantic)]
 #![allow(clippy::must_use_candidate, clippy::missing_panics_doc)]
+pub mod config;
+pub mod diagnostic;
 pub mod expression;
 pub mod formatter;
 pub mod lexer;
 pub mod parser;
+pub mod quoting;
 pub mod token;
+pub mod types;
 
 use std::{
     fmt::Write,
-    fs::{read_to_string, File},
-    os::unix::process::CommandExt,
-    process::Command,
+    fs::{self, File, Permissions},
+    io,
+    os::unix::{fs::PermissionsExt, process::CommandExt},
+    path::Path,
+    process::{self, Command},
 };
 
-use crate::{expression::Expression, lexer::Lexer, parser::Parser, token::Token};
+use crate::{
+    diagnostic::Diagnostics,
+    expression::{Expression, DEFER_RUNTIME, PARALLEL_RUNTIME, PIPELINE_RUNTIME},
+    lexer::Lexer,
+    parser::Parser,
+    token::{Kind, Token},
+};
 
-pub struct Script {
-    tokens: Vec<Token>,
-    expressions: Vec<Expression>,
+/// Intermediate results of a compilation, passed to the debug hook.
+#[derive(Debug)]
+pub enum DebugEvent<'a> {
+    Tokens(&'a [Token]),
+    Expressions(&'a [Expression]),
 }
 
-impl Script {
-    pub fn from_file(path: &str) -> Self {
-        let input = read_to_string(path).expect("Cannot open source file");
-        Script::from_string(&input)
+/// Callback receiving the intermediate results of a compilation.
+pub type DebugHook = Box<dyn Fn(DebugEvent)>;
+
+/// Lines written at the start of a compiled script, after the shebang.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Preamble {
+    /// Stops the script on the first failing command, unset variable or
+    /// failing stage of a pipeline, with `set -euo pipefail`.
+    pub strict: bool,
+    /// Characters that bash splits words on.
+    pub ifs: Option<String>,
+}
+
+impl Preamble {
+    fn write(&self) -> String {
+        let mut output = String::new();
+
+        if self.strict {
+            output.push_str("set -euo pipefail\n");
+        }
+
+        if let Some(ifs) = &self.ifs {
+            writeln!(output, "IFS={}", quoting::ansi_c(ifs)).unwrap();
+        }
+
+        output
+    }
+}
+
+/// Settings of a compilation.
+#[derive(Default)]
+pub struct Options {
+    debug_hook: Option<DebugHook>,
+    preamble: Preamble,
+    strip_comments: bool,
+}
+
+impl Options {
+    /// Writes `preamble` at the start of the compiled script.
+    #[must_use]
+    pub fn with_preamble(mut self, preamble: Preamble) -> Self {
+        self.preamble = preamble;
+        self
+    }
+
+    /// Calls `hook` with the output of each stage of the compilation.
+    #[must_use]
+    pub fn with_debug_hook(mut self, hook: impl Fn(DebugEvent) + 'static) -> Self {
+        self.debug_hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Leaves the comments of the source out of the compiled script.
+    #[must_use]
+    pub fn with_strip_comments(mut self, strip_comments: bool) -> Self {
+        self.strip_comments = strip_comments;
+        self
+    }
+
+    fn debug(&self, event: DebugEvent) {
+        if let Some(hook) = &self.debug_hook {
+            hook(event);
+        }
     }
+}
 
-    pub fn from_string(input: &str) -> Self {
-        let mut lexer = Lexer::from_string(input);
-        let tokens = lexer.parse();
+pub struct Script;
 
-        dbg!(&tokens);
+impl Script {
+    /// Compiles the sash source `input`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the diagnostics of the lexer when the input contains invalid
+    /// characters, or the diagnostics of the parser otherwise.
+    #[allow(clippy::needless_pass_by_value)]
+    pub fn compile(input: &str, options: Options) -> Result<Compiled, Diagnostics> {
+        let tokens = Lexer::from_string(input).parse()?;
+        options.debug(DebugEvent::Tokens(&tokens));
 
-        let mut parser = Parser::from_tokens(tokens.clone());
-        let expressions = parser.parse();
+        let mut parsed_tokens = tokens.clone();
+        if options.strip_comments {
+            parsed_tokens.retain(|token| token.kind != Kind::Comment);
+        }
 
-        dbg!(&expressions);
+        let mut parser = Parser::from_tokens(parsed_tokens);
+        let expressions = parser.parse()?;
+        let warnings = parser.warnings().to_vec().into();
+        options.debug(DebugEvent::Expressions(&expressions));
 
-        Script {
+        Ok(Compiled {
             tokens,
             expressions,
+            preamble: options.preamble,
+            warnings,
+            pipelines: parser.has_pipelines(),
+            parallels: parser.has_parallels(),
+            defers: parser.has_defers(),
+        })
+    }
+
+    /// Formats the sash source `input`, indenting every line by four spaces
+    /// for each bracket that is open at its start and removing trailing
+    /// whitespace. Lines continuing the expression of the line before them
+    /// are indented once more. Lines inside of multiline strings are kept as
+    /// they are.
+    ///
+    /// # Errors
+    ///
+    /// Returns the diagnostics of the lexer when the input contains invalid
+    /// characters.
+    pub fn format(input: &str) -> Result<String, Diagnostics> {
+        let tokens = Lexer::from_string(input).parse()?;
+
+        let mut output = String::new();
+        let mut tokens = tokens.iter().peekable();
+        let mut depth: usize = 0;
+        let mut line_start = 0;
+        let mut previous_end = 0;
+        let mut previous_kind = None;
+        let mut previous_opened = false;
+
+        for line in input.split_inclusive('\n') {
+            let line_end = line_start + line.len();
+            let inside_string = previous_end > line_start;
+
+            let mut closing = 0;
+            let mut opened: usize = 0;
+            let mut unmatched = 0;
+            let mut leading = true;
+            // A bracket opened on the line before already indents this one
+            let continued = (!previous_opened
+                && previous_kind
+                    .is_some_and(|kind: Kind| kind.continues_line() && kind != Kind::Coma))
+                || tokens
+                    .peek()
+                    .is_some_and(|token| token.span.start < line_end && token.kind == Kind::Period);
+
+            while let Some(token) = tokens.next_if(|token| token.span.start < line_end) {
+                match token.kind {
+                    Kind::RightBrace | Kind::RightBracket | Kind::RightParen if leading => {
+                        closing += 1;
+                    }
+                    Kind::RightBrace | Kind::RightBracket | Kind::RightParen => {
+                        if opened > 0 {
+                            opened -= 1;
+                        } else {
+                            unmatched += 1;
+                        }
+                    }
+                    Kind::LeftBrace | Kind::LeftBracket | Kind::LeftParen => {
+                        leading = false;
+                        opened += 1;
+                    }
+                    _ => leading = false,
+                }
+
+                previous_end = previous_end.max(token.span.end);
+                if token.kind != Kind::Newline {
+                    previous_kind = Some(token.kind);
+                }
+            }
+
+            let trimmed = line.trim();
+
+            if inside_string {
+                output.push_str(line.strip_suffix('\n').unwrap_or(line));
+            } else if !trimmed.is_empty() {
+                let indent = depth.saturating_sub(closing) + usize::from(continued);
+                output.push_str(&"    ".repeat(indent));
+                output.push_str(trimmed);
+            }
+            output.push('\n');
+
+            depth = depth.saturating_sub(closing + unmatched) + opened;
+            previous_opened = opened > 0;
+            line_start = line_end;
         }
+
+        Ok(output)
+    }
+}
+
+/// A successfully compiled script.
+#[derive(Debug, Clone)]
+pub struct Compiled {
+    tokens: Vec<Token>,
+    expressions: Vec<Expression>,
+    preamble: Preamble,
+    warnings: Diagnostics,
+    /// Whether the script has pipelines or reads `statuses`, which need
+    /// `PIPELINE_RUNTIME`.
+    pipelines: bool,
+    /// Whether the script has parallel for loops, which need `PARALLEL_RUNTIME`.
+    parallels: bool,
+    /// Whether the script has `defer` blocks, which need `DEFER_RUNTIME`.
+    defers: bool,
+}
+
+impl Compiled {
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
+    pub fn expressions(&self) -> &[Expression] {
+        &self.expressions
     }
 
-    pub fn debug(&self) {
-        println!("{:#?} {:#?}", self.tokens, self.expressions);
+    /// Likely mistakes found in the script, which did not stop it from compiling.
+    pub fn warnings(&self) -> &Diagnostics {
+        &self.warnings
     }
 
-    pub fn get_code(&self) -> String {
+    /// Writes the script as bash code.
+    pub fn code(&self) -> String {
         let mut output = String::new();
 
-        for expression in &self.expressions {
+        if self.pipelines {
+            output.write_str(PIPELINE_RUNTIME).unwrap();
+        }
+
+        if self.parallels {
+            output.write_str(PARALLEL_RUNTIME).unwrap();
+        }
+
+        if self.defers {
+            output.write_str(DEFER_RUNTIME).unwrap();
+        }
+
+        // Functions are written first, so that they can be called before the
+        // place they are declared in
+        let (functions, statements): (Vec<&Expression>, Vec<&Expression>) = self
+            .expressions
+            .iter()
+            .partition(|expression| expression.is_function_declaration());
+
+        for expression in functions.into_iter().chain(statements) {
             output.write_str(expression.write().as_str()).unwrap();
             output.write_char('\n').unwrap();
         }
@@ -58,12 +278,431 @@ impl Script {
         output
     }
 
-    pub fn write_file(&self, path: &str) {
-        let mut file = File::options().create(true).write(true).open(path).unwrap();
-        std::io::Write::write_fmt(&mut file, format_args!("{}", self.get_code())).unwrap();
+    /// Writes the script as a complete bash program, starting with a shebang
+    /// and the preamble.
+    pub fn script(&self) -> String {
+        format!(
+            "#!/usr/bin/env bash\n{}{}",
+            self.preamble.write(),
+            self.code()
+        )
+    }
+
+    /// Writes the script to an executable file at `path`. The file is
+    /// replaced at once, so it is never left partially written.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when the file cannot be created or written.
+    pub fn write_file(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{}.tmp", process::id()));
+        let temporary = path.with_file_name(name);
+
+        let result = File::create(&temporary).and_then(|mut file| {
+            io::Write::write_all(&mut file, self.script().as_bytes())?;
+            file.set_permissions(Permissions::from_mode(0o755))?;
+            file.sync_all()?;
+            fs::rename(&temporary, path)
+        });
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temporary);
+        }
+
+        result
+    }
+
+    /// Replaces the current process with bash running the script, returning
+    /// only when bash cannot be started.
+    pub fn interpret(&self) -> io::Error {
+        Command::new("bash").args(["-c", &self.script()]).exec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(source: &str) -> Compiled {
+        Script::compile(source, Options::default()).unwrap_or_else(|diagnostics| {
+            panic!("{}", diagnostics.render(source, "test.sash", false))
+        })
+    }
+
+    /// Compiles and runs `source`, giving back its exit status, output and errors.
+    fn run(source: &str) -> (i32, String, String) {
+        let output = Command::new("bash")
+            .args(["-c", &compile(source).script()])
+            .output()
+            .expect("Cannot run bash");
+
+        (
+            output.status.code().unwrap_or(-1),
+            String::from_utf8_lossy(&output.stdout).into_owned(),
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        )
+    }
+
+    fn stdout(source: &str) -> String {
+        run(source).1
+    }
+
+    #[test]
+    fn for_loops() {
+        let source = r#"for i in 0..3 {
+    print(i)
+}
+for x in [4, 5] {
+    print(x)
+}
+for line in printf!("%s\n", "a b", "c") {
+    print("<{line}>")
+}"#;
+        assert_eq!(stdout(source), "0\n1\n2\n4\n5\n<a b>\n<c>\n");
+    }
+
+    #[test]
+    fn functions_can_be_called_before_their_declaration() {
+        let source = "print(add(1, 2))\nfunction add(a, b) {\n    let sum = a + b\n    return sum\n}\nprint(sum)";
+        assert_eq!(stdout(source), "3\n\n");
     }
 
-    pub fn interpret(&self) {
-        Command::new("bash").args(["-c", &self.get_code()]).exec();
+    #[test]
+    fn functions_run_in_the_shell_of_the_script() {
+        let source = r#"let count = 0
+function bump() {
+    print("bumping")
+    count = count + 1
+    return count
+}
+bump()
+let value = bump()
+print("{value} {count}")
+function fib(n) {
+    if (n < 2) {
+        return n
+    }
+    return fib(n - 1) + fib(n - 2)
+}
+print(fib(10))
+function quit() {
+    exit(3)
+}
+quit()
+print("unreachable")"#;
+        assert_eq!(
+            run(source),
+            (3, "bumping\nbumping\n2 2\n55\n".into(), String::new())
+        );
+    }
+
+    #[test]
+    fn arrays() {
+        let source = r#"let xs = ["a", "b", "c", "d"]
+push(xs, "e")
+xs[0] = "z"
+print(len(xs), xs[0], xs[1..3], xs[3..])"#;
+        assert_eq!(stdout(source), "5 z b c d e\n");
+    }
+
+    #[test]
+    fn slices_count_negative_bounds_from_the_end() {
+        let source = r#"let xs = ["a", "b", "c", "d", "e"]
+let i = -2
+print("<{xs[-2..]}>", "<{xs[1..-1]}>", "<{xs[3..1]}>", "<{xs[..i]}>")"#;
+        assert_eq!(stdout(source), "<d e> <b c d> <> <a b c>\n");
+    }
+
+    fn errors(source: &str) -> Vec<String> {
+        match Script::compile(source, Options::default()) {
+            Ok(_) => panic!("expected {:?} not to compile", source),
+            Err(diagnostics) => diagnostics
+                .into_iter()
+                .map(|diagnostic| diagnostic.message)
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn maps() {
+        let source = r#"let ports = { "web": 80, "db": 5432 }
+ports["cache"] = 6379
+print(ports["web"], len(keys(ports)))
+if (has(ports, "db") && !has(ports, "x")) {
+    print("has db")
+}"#;
+        assert_eq!(stdout(source), "80 3\nhas db\n");
+    }
+
+    #[test]
+    fn map_literals_are_only_assigned_to_maps() {
+        assert_eq!(
+            stdout("let m = {\"a\": 1}\nm = {\"b\": 2}\nprint(m[\"b\"])"),
+            "2\n"
+        );
+        assert!(errors("let m = 1\nm = {\"a\": 1}")[0].contains("Cannot assign a map"));
+    }
+
+    #[test]
+    fn conditions() {
+        let source = r#"let a = 10
+if (a > 9 && "b" > "a" || false) {
+    print("yes")
+} else if (a == 10) {
+    print("elif")
+} else {
+    print("no")
+}
+if (!(a < 5)) {
+    print("not")
+}"#;
+        assert_eq!(stdout(source), "yes\nnot\n");
+    }
+
+    #[test]
+    fn functions_in_conditions_are_true_when_they_return_true() {
+        let source = r#"if (is_prod("dev") || !is_prod("prod") || false && is_prod("x")) {
+    print("wrong")
+} else {
+    print("right")
+}
+function is_prod(env) {
+    print("checking {env}")
+    if (env == "prod") {
+        return true
+    }
+    return false
+}"#;
+        assert_eq!(stdout(source), "checking dev\nchecking prod\nright\n");
+    }
+
+    #[test]
+    fn comparisons_depend_on_the_types() {
+        let source = "if (10 > 9 && \"10\" < \"9\") {\n    print(\"typed\")\n}";
+        assert_eq!(stdout(source), "typed\n");
+        assert!(errors("if (1 == \"a\") {\n}")[0].contains("Cannot compare"));
+    }
+
+    #[test]
+    fn arithmetic() {
+        let source = "let a = 0\nlet b = 1\nlet i = 0\nwhile (i < 10) {\n    let t = b\n    b = a + b\n    a = t\n    i = i + 1\n}\nprint(a)";
+        assert_eq!(stdout(source), "55\n");
+        assert_eq!(
+            stdout("print(2 - 3 - 4, 2 ** 3 ** 2, 1 << 2 + 1, -7 % 3)"),
+            "-5 512 8 -1\n"
+        );
+        assert_eq!(stdout("let a = 7\nprint(-a % 3, 0 - -a)"), "-1 7\n");
+    }
+
+    #[test]
+    fn errors_inside_of_a_block_with_a_failed_header_are_reported() {
+        let errors =
+            errors("function f(a b) {\n    let x = = 1\n}\nfor i in {\n    let y = ) 1\n}");
+        assert_eq!(errors.len(), 4, "{errors:?}");
+    }
+
+    #[test]
+    fn comments_inside_of_expressions_and_before_else() {
+        let source = r#"let m = {
+    # the web port
+    "web": 80
+}
+print(m["web"],
+    # the second argument
+    1)
+if (false) {
+    print("no")
+}
+# before else
+else {
+    print("else")
+}"#;
+        assert_eq!(stdout(source), "80 1\nelse\n");
+        assert!(compile(source).code().contains("# before else"));
+    }
+
+    #[test]
+    fn strings_reach_commands_as_one_word() {
+        let source = r#"let file = "my notes.txt"
+printf!("[%s]", file, "*", "$HOME", "it's", "say \"hi\"")"#;
+        assert_eq!(
+            stdout(source),
+            r#"[my notes.txt][*][$HOME][it's][say "hi"]"#
+        );
+        assert_eq!(
+            stdout("let xs = [\"a b\", \"c\"]\nprintf!(\"[%s]\", xs)"),
+            "[a b][c]"
+        );
+    }
+
+    #[test]
+    fn interpolated_strings() {
+        let source = "let n = 2\nprint(\"{n} files, {n * 3} bytes, $5 \\{x\\}\")";
+        assert_eq!(stdout(source), "2 files, 6 bytes, $5 {x}\n");
+    }
+
+    #[test]
+    fn new_lines_in_interpolated_strings_are_not_indented() {
+        let source = "let name = \"x\"\nfunction show() {\n    let msg = \"a\n{name}\tb\"\n    print(msg)\n}\nshow()";
+        assert_eq!(stdout(source), "a\nx\tb\n");
+    }
+
+    #[test]
+    fn number_literals() {
+        assert_eq!(stdout("print(0xff + 0b1 + 0o10 + 1_000)"), "1264\n");
+        assert_eq!(stdout("let r = 1.5\nprint(r * 2 + 0.25)"), "3.25\n");
+    }
+
+    #[test]
+    fn floats_keep_their_digits_and_any_variable_name() {
+        let source = "let index = 1234567.5\nprint(index * 1, index + 0.1 + 0.2)\nif (index > 1.5) {\n    print(\"gt\")\n}";
+        assert_eq!(stdout(source), "1234567.5 1234567.8\ngt\n");
+    }
+
+    #[test]
+    fn loop_control() {
+        let source = r"let n = 0
+loop {
+    n = n + 1
+    if (n == 2) {
+        break
+    }
+}
+do {
+    n = n + 1
+} while (n < 1)
+print(n)
+outer: for a in [1, 2] {
+    for b in [1, 2] {
+        if (b == 2) {
+            continue outer
+        }
+        print(a, b)
+    }
+}";
+        assert_eq!(stdout(source), "3\n1 1\n2 1\n");
+    }
+
+    #[test]
+    fn status_can_be_compared_more_than_once() {
+        let source = r#"sh!("-c", "exit 2")
+if (status == 0 || status == 2) {
+    print("ok")
+}
+sh!("-c", "exit 3")
+if (status == 1) {
+    print("wrong")
+} else if (status == 3) {
+    print("elif")
+}"#;
+        assert_eq!(stdout(source), "ok\nelif\n");
+    }
+
+    #[test]
+    fn run_gives_back_status_and_output() {
+        let source = r#"let result = run!(sh!("-c", "echo out; echo err >&2; exit 4"))
+print(result["code"], result["stdout"], result["stderr"])"#;
+        assert_eq!(stdout(source), "4 out err\n");
+    }
+
+    #[test]
+    fn exit_status_of_the_script() {
+        assert_eq!(run("exit(3)").0, 3);
+    }
+
+    #[test]
+    fn statuses_of_the_last_pipeline() {
+        let source = r#"false!().true!()
+if (statuses[0] != 0 || statuses[1] != 0) {
+    print("failed", statuses[0], statuses[1])
+}"#;
+        assert_eq!(stdout(source), "failed 1 0\n");
+        assert!(compile(source).code().contains("set -o pipefail"));
+    }
+
+    #[test]
+    fn statuses_of_chains_in_conditions_but_not_in_values() {
+        let source = r#"if (!false!().cat!()) {
+    print(statuses)
+}
+if (true && true!().false!()) {
+    print("no")
+}
+print(statuses)"#;
+        assert_eq!(stdout(source), "1 0\n0 1\n");
+
+        let errors = errors("let out = false!().cat!()\nprint(statuses)");
+        assert!(errors[0].contains("statuses is not set"), "{:?}", errors);
+        assert_eq!(
+            stdout("let out = false!().cat!()\nfalse!().cat!()\nprint(statuses)"),
+            "1 0\n"
+        );
+    }
+
+    #[test]
+    fn chains() {
+        let source = r#"let n = printf!("%s\n", "b", "apple", "avocado").filter(/^a/).count()
+print(n)
+printf!("%s\n", "c", "b", "b", "a").sort().uniq().map(s -> "<{s}>").take(2)
+let lines = printf!("%s\n", "x y", "z").lines()
+print(len(lines), lines[0])"#;
+        assert_eq!(stdout(source), "2\n<a>\n<b>\n2 x y\n");
+    }
+
+    #[test]
+    fn spawn_and_wait() {
+        let source = r#"let job = spawn sh!("-c", "exit 3")
+let code = wait(job)
+print(code)"#;
+        assert_eq!(stdout(source), "3\n");
+    }
+
+    #[test]
+    fn parallel_waits_only_for_its_jobs_and_fails_with_them() {
+        let source = r#"let other = spawn sleep!("1")
+parallel(max: 2) for x in [0, 5, 0] {
+    sh!("-c", "exit {x}")
+}
+print(status)
+kill!("-0", other)
+print(status)"#;
+        assert_eq!(stdout(source), "5\n0\n");
+    }
+
+    #[test]
+    fn parallel_takes_an_expression_as_the_maximum() {
+        let source = r"let n = 1
+parallel(max: n + 1) for x in [1, 2, 3] {
+    print(x * 2)
+}
+print(status)";
+        assert!(compile(source).script().contains(r#""$((n + 1))""#));
+        let mut lines: Vec<String> = stdout(source).lines().map(String::from).collect();
+        lines.sort();
+        assert_eq!(lines, ["0", "2", "4", "6"]);
+    }
+
+    #[test]
+    fn deferred_blocks_run_last_first() {
+        let source = r#"defer {
+    print("done")
+}
+function get() {
+    defer {
+        print("first")
+    }
+    defer {
+        print("second {other()}")
+    }
+    return 5
+}
+function other() {
+    return 7
+}
+let v = get()
+print("v = {v}")"#;
+        assert_eq!(stdout(source), "second 7\nfirst\nv = 5\ndone\n");
     }
 }
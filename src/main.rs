@@ -1,40 +1,301 @@
 #![warn(clippy::pedantic)]
 extern crate sash_lang;
-use sash_lang::Script;
+use sash_lang::{config::Config, diagnostic::Diagnostics, Compiled, Options, Preamble, Script};
 
-use std::env;
+use std::{
+    env,
+    fs::{canonicalize, read_to_string, write},
+    io::{stderr, IsTerminal},
+    os::unix::process::ExitStatusExt,
+    path::Path,
+    process::{self, Command},
+};
+
+const USAGE: &str = "\
+Usage: sash <command> [options] <input>
+
+Commands:
+    build <input> [-o <output>]             Compile the script to a bash file
+    run <input> [-- <args>...]              Compile the script and run it with bash
+    check <input>                           Only report errors in the script
+    emit <input> [--tokens|--ast|--bash]    Print an intermediate form of the script
+    fmt <input> [--check]                   Format the script in place
+
+Options:
+    --strict, --no-strict                   Whether to start the script with set -euo pipefail
+    --ifs=<characters>                      Set IFS at the start of the script
+    --strip-comments                        Leave comments out of the generated bash
+    -q, --quiet                             Do not print warnings and progress messages
+    --color=<auto|always|never>             When to highlight diagnostics
+    -h, --help                              Print this help";
+
+/// Exit status of an invalid command line.
+const USAGE_ERROR: i32 = 2;
+
+/// Exit status of a script that failed to compile or of a failed file operation.
+const FAILURE: i32 = 1;
+
+enum Subcommand {
+    Build { output: Option<String> },
+    Run { args: Vec<String> },
+    Check,
+    Emit { form: Form },
+    Fmt { check: bool },
+}
+
+#[derive(Clone, Copy)]
+enum Form {
+    Tokens,
+    Ast,
+    Bash,
+}
 
 struct Args {
+    subcommand: Subcommand,
     input_filename: String,
-    output_filename: Option<String>,
+    quiet: bool,
+    color: bool,
+    strict: Option<bool>,
+    ifs: Option<String>,
+    strip_comments: bool,
 }
 
 fn main() {
-    let args = parse_args();
+    let args = parse_args().unwrap_or_else(|error| {
+        eprintln!("error: {error}\n\n{USAGE}");
+        process::exit(USAGE_ERROR);
+    });
+
+    let source = read_to_string(&args.input_filename).unwrap_or_else(|error| {
+        eprintln!("error: Cannot open {}: {}", args.input_filename, error);
+        process::exit(FAILURE);
+    });
+
+    let status = match &args.subcommand {
+        Subcommand::Build { output } => {
+            let code = compile(&args, &source);
+            let output = output.clone().unwrap_or_else(|| {
+                Path::new(&args.input_filename)
+                    .with_extension("sh")
+                    .to_string_lossy()
+                    .into_owned()
+            });
+
+            if let Err(error) = code.write_file(&output) {
+                eprintln!("error: Cannot write {output}: {error}");
+                process::exit(FAILURE);
+            }
+
+            if !args.quiet {
+                eprintln!("Compiled {} to {output}", args.input_filename);
+            }
+            0
+        }
+        Subcommand::Run { args: script_args } => {
+            let code = compile(&args, &source);
+            run(&code, &args.input_filename, script_args)
+        }
+        Subcommand::Check => {
+            compile(&args, &source);
+            0
+        }
+        Subcommand::Emit { form } => {
+            let code = compile(&args, &source);
+
+            match form {
+                Form::Tokens => println!("{:#?}", code.tokens()),
+                Form::Ast => println!("{:#?}", code.expressions()),
+                Form::Bash => print!("{}", code.script()),
+            }
+            0
+        }
+        Subcommand::Fmt { check } => {
+            let formatted = Script::format(&source).unwrap_or_else(|diagnostics| {
+                report(&args, &source, &args.input_filename, &diagnostics)
+            });
+
+            if formatted == source {
+                0
+            } else if *check {
+                if !args.quiet {
+                    eprintln!("{} is not formatted", args.input_filename);
+                }
+                FAILURE
+            } else {
+                if let Err(error) = write(&args.input_filename, formatted) {
+                    eprintln!("error: Cannot write {}: {}", args.input_filename, error);
+                    process::exit(FAILURE);
+                }
+
+                if !args.quiet {
+                    eprintln!("Formatted {}", args.input_filename);
+                }
+                0
+            }
+        }
+    };
+
+    process::exit(status);
+}
 
-    let code = Script::from_file(&args.input_filename);
+fn compile(args: &Args, source: &str) -> Compiled {
+    let options = Options::default()
+        .with_preamble(preamble(args))
+        .with_strip_comments(args.strip_comments);
 
-    if let Some(path) = args.output_filename {
-        code.write_file(&path);
-    } else {
-        print!("------ generated code ------\n{}", code.get_code());
-        println!("--------- output ----------");
-        code.interpret();
+    let code = Script::compile(source, options)
+        .unwrap_or_else(|diagnostics| report(args, source, &args.input_filename, &diagnostics));
+
+    if !args.quiet && !code.warnings().is_empty() {
+        eprint!(
+            "{}",
+            code.warnings()
+                .render(source, &args.input_filename, args.color)
+        );
     }
+
+    code
 }
 
-fn parse_args() -> Args {
-    let args: Vec<String> = env::args().collect();
+/// Combines the preamble settings of the project config with the command line,
+/// which takes precedence.
+fn preamble(args: &Args) -> Preamble {
+    let input = canonicalize(&args.input_filename)
+        .unwrap_or_else(|_| Path::new(&args.input_filename).to_path_buf());
 
-    let input_filename = args
-        .get(1)
-        .expect("You need to pass filename as an argument!")
-        .clone();
+    let config = match Config::find(&input) {
+        Some(path) => {
+            let path = path.to_string_lossy().into_owned();
+            let source = read_to_string(&path).unwrap_or_else(|error| {
+                eprintln!("error: Cannot open {path}: {error}");
+                process::exit(FAILURE);
+            });
 
-    let output_filename = args.get(2).cloned();
+            Config::parse(&source)
+                .unwrap_or_else(|diagnostics| report(args, &source, &path, &diagnostics.into()))
+        }
+        None => Config::default(),
+    };
 
-    Args {
-        input_filename,
-        output_filename,
+    Preamble {
+        strict: args.strict.or(config.strict).unwrap_or(false),
+        ifs: args.ifs.clone().or(config.ifs),
     }
 }
+
+fn report(args: &Args, source: &str, path: &str, diagnostics: &Diagnostics) -> ! {
+    eprint!("{}", diagnostics.render(source, path, args.color));
+    process::exit(FAILURE);
+}
+
+/// Runs the compiled script with bash and returns its exit status. A script
+/// killed by a signal exits with 128 plus the signal number, like in bash.
+fn run(code: &Compiled, path: &str, script_args: &[String]) -> i32 {
+    let status = Command::new("bash")
+        .arg("-c")
+        .arg(code.script())
+        .arg(path)
+        .args(script_args)
+        .status()
+        .unwrap_or_else(|error| {
+            eprintln!("error: Cannot execute bash: {error}");
+            process::exit(FAILURE);
+        });
+
+    status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(FAILURE)
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = env::args().skip(1);
+
+    let mut subcommand = None;
+    let mut input_filename = None;
+    let mut output = None;
+    let mut form = None;
+    let mut check = false;
+    let mut quiet = false;
+    let mut color = "auto".to_string();
+    let mut strict = None;
+    let mut ifs = None;
+    let mut strip_comments = false;
+    let mut script_args = Vec::new();
+    // Options of a single command, checked once the command is known
+    let mut command_options = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                process::exit(0);
+            }
+            "-q" | "--quiet" => quiet = true,
+            "-o" | "--output" => {
+                output = Some(args.next().ok_or("Expected a file name after -o")?);
+                command_options.push((arg, "build"));
+            }
+            "--tokens" | "--ast" | "--bash" => {
+                form = Some(match arg.as_str() {
+                    "--tokens" => Form::Tokens,
+                    "--ast" => Form::Ast,
+                    _ => Form::Bash,
+                });
+                command_options.push((arg, "emit"));
+            }
+            "--check" => {
+                check = true;
+                command_options.push((arg, "fmt"));
+            }
+            "--strict" => strict = Some(true),
+            "--no-strict" => strict = Some(false),
+            "--strip-comments" => strip_comments = true,
+            "--" => {
+                script_args.extend(args.by_ref());
+                command_options.push((arg, "run"));
+            }
+            _ if arg.starts_with("--color=") => color = arg["--color=".len()..].to_string(),
+            _ if arg.starts_with("--ifs=") => ifs = Some(arg["--ifs=".len()..].to_string()),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {arg}")),
+            _ if subcommand.is_none() => subcommand = Some(arg),
+            _ if input_filename.is_none() => input_filename = Some(arg),
+            _ => return Err(format!("Unexpected argument {arg}")),
+        }
+    }
+
+    let name = subcommand.ok_or("Expected a command")?;
+    let subcommand = match name.as_str() {
+        "build" => Subcommand::Build { output },
+        "run" => Subcommand::Run { args: script_args },
+        "check" => Subcommand::Check,
+        "emit" => Subcommand::Emit {
+            form: form.unwrap_or(Form::Bash),
+        },
+        "fmt" => Subcommand::Fmt { check },
+        _ => return Err(format!("Unknown command {name}")),
+    };
+
+    if let Some((option, command)) = command_options.iter().find(|(_, command)| *command != name) {
+        return Err(format!(
+            "{option} can only be used with {command}, not with {name}"
+        ));
+    }
+
+    let color = match color.as_str() {
+        "always" => true,
+        "never" => false,
+        "auto" => stderr().is_terminal() && env::var_os("NO_COLOR").is_none(),
+        _ => return Err(format!("Invalid color mode {color}")),
+    };
+
+    Ok(Args {
+        subcommand,
+        input_filename: input_filename.ok_or("Expected an input file")?,
+        quiet,
+        color,
+        strict,
+        ifs,
+        strip_comments,
+    })
+}
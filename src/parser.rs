@@ -1,52 +1,512 @@
+use std::collections::HashMap;
+
 #[allow(clippy::wildcard_imports)]
 use crate::{
+    diagnostic::Diagnostic,
     expression::*,
-    token::{Kind, Token},
+    token::{Kind, Span, Token, UNARY_PRECEDENCE},
+    types::{self, Type},
 };
 
+/// Chain methods that redirect the input or output of the command before them.
+const REDIRECTS: &[&str] = &[
+    "from_file",
+    "from_string",
+    "to_file",
+    "append_to",
+    "stderr_to_stdout",
+    "stderr_to",
+];
+
+/// Chain methods that process the lines of the output of the command before them.
+const CHAIN_BUILTINS: &[&str] = &["filter", "map", "take", "skip", "sort", "uniq", "count"];
+
+const BUILTIN_FUNCTIONS: &[&str] = &[
+    "print",
+    "compress",
+    "decompress",
+    "ls_archive",
+    "red",
+    "green",
+    "yellow",
+    "blue",
+    "magenta",
+    "cyan",
+    "len",
+    "keys",
+    "has",
+    "push",
+    "exit",
+    "wait",
+    "wait_all",
+];
+
+/// Builtins waiting for background jobs, whose exit status can only be stored
+/// in a variable.
+const WAITS: &[&str] = &["wait", "wait_all"];
+
 pub struct Parser {
     index: usize,
     tokens: Vec<Token>,
+    anonymous_counters: usize,
+    functions: HashMap<String, usize>,
+    variables: HashMap<String, Type>,
+    in_function: bool,
+    /// Labels of the loops around the current statement, from the outermost.
+    loops: Vec<Option<Token>>,
+    /// Label of the loop that is being parsed, until its body starts.
+    loop_label: Option<Token>,
+    /// Whether a chain pipes the output of a command into another, or
+    /// `statuses` is read.
+    pipelines: bool,
+    /// Number of `defer` blocks parsed so far.
+    defers: usize,
+    /// Number of parallel for loops parsed so far.
+    parallels: usize,
+    /// While parsing the condition of a statement, whether it reads `status`.
+    condition_status: Option<bool>,
+    /// Where the last chain of more than one command is, when its output is
+    /// used as a value. It runs in a subshell, which cannot save `statuses`.
+    unsaved_statuses: Option<Span>,
+    /// Calls to user functions whose values are used by the statement or
+    /// condition being parsed, made before it.
+    calls: Vec<(Token, FnCall)>,
+    diagnostics: Vec<Diagnostic>,
+    warnings: Vec<Diagnostic>,
+    panic_mode: bool,
 }
 
 #[allow(clippy::unnecessary_wraps)]
 impl Parser {
     pub fn from_tokens(tokens: Vec<Token>) -> Self {
-        Parser { index: 0, tokens }
+        let mut parser = Parser {
+            index: 0,
+            tokens,
+            anonymous_counters: 0,
+            functions: HashMap::new(),
+            variables: HashMap::new(),
+            in_function: false,
+            loops: Vec::new(),
+            loop_label: None,
+            pipelines: false,
+            defers: 0,
+            parallels: 0,
+            condition_status: None,
+            calls: Vec::new(),
+            unsaved_statuses: None,
+            diagnostics: Vec::new(),
+            warnings: Vec::new(),
+            panic_mode: false,
+        };
+
+        parser.collect_functions();
+        parser
+    }
+
+    /// Finds every `function name(params)` signature up front, so that
+    /// functions can be called before the place they are declared in.
+    fn collect_functions(&mut self) {
+        let mut declarations: HashMap<String, Span> = HashMap::new();
+
+        let mut iter = self.tokens.iter().peekable();
+        while let Some(token) = iter.next() {
+            if token.kind != Kind::Function {
+                continue;
+            }
+
+            let Some(name) = iter.next_if(|token| token.kind == Kind::Identifier) else {
+                continue;
+            };
+
+            let arity = iter
+                .by_ref()
+                .take_while(|token| token.kind != Kind::RightParen)
+                .filter(|token| token.kind == Kind::Identifier)
+                .count();
+
+            if let Some(previous) = declarations.get(&name.lexeme) {
+                self.diagnostics.push(
+                    Diagnostic::error(format!("Function \"{}\" is declared twice", name.lexeme))
+                        .with_label(name.span, "declared again here")
+                        .with_secondary_label(*previous, "first declared here"),
+                );
+            } else {
+                declarations.insert(name.lexeme.clone(), name.span);
+                self.functions.insert(name.lexeme.clone(), arity);
+            }
+        }
     }
 
-    pub fn parse(&mut self) -> Vec<Expression> {
+    /// Parses the tokens into a list of statements. A statement with a syntax
+    /// error is replaced by `Expression::Error` and parsing resumes at the
+    /// next statement, so that every error in the input is reported.
+    ///
+    /// # Errors
+    ///
+    /// Returns the diagnostics of all errors found in the input, followed by
+    /// the warnings.
+    pub fn parse(&mut self) -> Result<Vec<Expression>, Vec<Diagnostic>> {
         let mut expressions = Vec::new();
 
-        while self.index < self.tokens.len() {
-            match self.seek_expression() {
+        loop {
+            self.skip_separators();
+            if self.index >= self.tokens.len() {
+                break;
+            }
+
+            let start = self.index;
+
+            match self.match_statement() {
                 Some(expression) => expressions.push(expression),
-                None => self.skip_token(),
+                None if self.index == start => self.skip_token(),
+                None => {}
             }
         }
 
-        expressions
+        if self.diagnostics.is_empty() {
+            Ok(expressions)
+        } else {
+            let mut diagnostics = std::mem::take(&mut self.diagnostics);
+            diagnostics.extend(self.warnings.iter().cloned());
+            Err(diagnostics)
+        }
     }
 
     fn seek_expression(&mut self) -> Option<Expression> {
-        match self.tokens.get(self.index).unwrap().kind {
+        match self.get_token()?.kind {
+            Kind::Identifier if self.peek_token(Kind::Colon).is_some() => self.match_labeled_loop(),
             Kind::Identifier => self.match_identifier(),
             Kind::Command => self.match_fn_chain(),
             Kind::Let => self.match_var_declaration(),
             Kind::If => self.match_if_statement(),
             Kind::Else => self.match_else_if_statement(),
             Kind::While => self.match_while_statement(),
-            Kind::Comment => None,
-            _ => {
-                println!(
-                    "Unknown expression: {:#?}",
-                    self.tokens.get(self.index).unwrap()
-                );
-                None
+            Kind::Loop => self.match_loop_statement(),
+            Kind::Do => self.match_do_while_statement(),
+            Kind::Break | Kind::Continue => self.match_loop_control(),
+            Kind::For => self.match_for_statement(),
+            Kind::Function => self.match_function_declaration(),
+            Kind::Return => self.match_return_statement(),
+            Kind::Spawn => self.match_spawn(),
+            Kind::Parallel => self.match_parallel_statement(),
+            Kind::Defer => self.match_defer_statement(),
+            _ => self.syntax_error("Expected statement"),
+        }
+    }
+
+    /// Parses the next statement together with the comments right before it.
+    /// Comments at the end of a block or of the file have no statement to
+    /// attach to and are kept on their own.
+    fn match_statement(&mut self) -> Option<Expression> {
+        let mut comments = Vec::new();
+        while let Some(comment) = self.consume_token(Kind::Comment) {
+            comments.push(comment);
+            self.skip_separators();
+        }
+
+        let outer = std::mem::take(&mut self.calls);
+        let start = self.index;
+        let statement = match self.get_token() {
+            None => None,
+            Some(token) if token.kind == Kind::RightBrace && !comments.is_empty() => None,
+            Some(_) => match self.seek_expression() {
+                Some(mut expression) => {
+                    if let Expression::FnChain(chain) = &mut expression {
+                        self.save_statuses(chain);
+                    }
+
+                    self.expect_statement_end();
+                    Some(expression)
+                }
+                None if self.panic_mode => Some(self.recover(start)),
+                None => None,
+            },
+        };
+        let calls = std::mem::replace(&mut self.calls, outer);
+        let statement = statement.map(|statement| self.make_calls(calls, statement));
+
+        if comments.is_empty() {
+            statement
+        } else {
+            Some(Expression::Commented(CommentedExpr {
+                comments,
+                statement: statement.map(Box::new),
+            }))
+        }
+    }
+
+    /// Saves the statuses of the commands of a chain that does not run in a
+    /// subshell, which is a statement or a condition.
+    fn save_statuses(&mut self, chain: &mut FnChain) {
+        if chain.invocations.len() > 1 && !chain.lines {
+            chain.save_statuses = true;
+            self.unsaved_statuses = None;
+        }
+    }
+
+    /// Skips the rest of the statement that started at `start` and failed to
+    /// parse, returning the placeholder that takes its place.
+    fn recover(&mut self, start: usize) -> Expression {
+        let span = self
+            .tokens
+            .get(start)
+            .map_or_else(Span::default, |token| token.span);
+
+        // A statement that failed on its first token would be retried forever
+        if self.index == start {
+            self.skip_token();
+        }
+
+        self.synchronize();
+        self.panic_mode = false;
+
+        Expression::Error(ErrorExpr { span })
+    }
+
+    /// Reports a statement that is followed by another one on the same line,
+    /// skipping the rest of the line.
+    fn expect_statement_end(&mut self) {
+        match self.get_token() {
+            Some(token)
+                if !token.kind.is_separator()
+                    && !matches!(token.kind, Kind::RightBrace | Kind::Comment) =>
+            {
+                self.syntax_error::<()>("Expected a new line or ; after the statement");
+                self.synchronize();
+                self.panic_mode = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn skip_separators(&mut self) {
+        while self
+            .get_token()
+            .is_some_and(|token| token.kind.is_separator())
+        {
+            self.skip_token();
+        }
+    }
+
+    /// Consumes a token of `kind` that is either next or at the start of the
+    /// next line, like the `{` of a block or an `else`.
+    fn consume_token_across_lines(&mut self, kind: Kind) -> Option<Token> {
+        let start = self.index;
+        self.skip_separators();
+
+        if self.tokens[start..self.index]
+            .iter()
+            .any(|token| token.kind == Kind::Semicolon)
+        {
+            self.index = start;
+            return None;
+        }
+
+        let token = self.consume_token(kind);
+        if token.is_none() {
+            self.index = start;
+        }
+        token
+    }
+
+    /// Skips tokens until the start of the next statement, which is the token
+    /// after the end of the line or the `}` closing the current block. Blocks
+    /// opened by the skipped tokens are skipped as a whole.
+    fn synchronize(&mut self) {
+        while let Some(token) = self.get_token() {
+            match token.kind {
+                Kind::RightBrace => break,
+                kind if kind.is_separator() => break,
+                Kind::LeftBrace if self.opens_block() => self.match_recovered_body(),
+                Kind::LeftBrace => self.skip_block(),
+                _ => self.skip_token(),
+            }
+        }
+    }
+
+    /// Whether the `{` at the current token opens a block rather than a map.
+    fn opens_block(&self) -> bool {
+        !self.index.checked_sub(1).is_some_and(|previous| {
+            matches!(
+                self.tokens[previous].kind,
+                Kind::Equal
+                    | Kind::LeftParen
+                    | Kind::LeftBracket
+                    | Kind::Coma
+                    | Kind::Colon
+                    | Kind::Return
+            )
+        })
+    }
+
+    /// Parses the block of a statement whose header failed to parse, so that
+    /// the errors inside of it are still reported.
+    fn match_recovered_body(&mut self) {
+        let header: Vec<Kind> = self.tokens[..self.index]
+            .iter()
+            .rev()
+            .map(|token| token.kind)
+            .take_while(|kind| {
+                !kind.is_separator() && !matches!(kind, Kind::LeftBrace | Kind::RightBrace)
+            })
+            .collect();
+
+        let in_function = self.in_function;
+        self.in_function |= header.contains(&Kind::Function);
+
+        let is_loop = header
+            .iter()
+            .any(|kind| matches!(kind, Kind::For | Kind::While | Kind::Parallel));
+        if is_loop {
+            self.loops.push(None);
+        }
+
+        self.panic_mode = false;
+        let _ = self.match_statement_body();
+
+        if is_loop {
+            self.loops.pop();
+        }
+        self.in_function = in_function;
+    }
+
+    fn skip_block(&mut self) {
+        let mut depth = 0;
+
+        while let Some(token) = self.get_token() {
+            match token.kind {
+                Kind::LeftBrace => depth += 1,
+                Kind::RightBrace => depth -= 1,
+                _ => {}
+            }
+
+            self.skip_token();
+
+            if depth == 0 {
+                break;
             }
         }
     }
 
+    /// Reports an error that makes the rest of the current statement
+    /// impossible to parse. Errors caused by the first one are not reported.
+    fn syntax_error<T>(&mut self, message: &str) -> Option<T> {
+        if !self.panic_mode {
+            let diagnostic = match self.get_token() {
+                Some(token) if token.kind == Kind::Newline => {
+                    Diagnostic::error(message).with_label(token.span, "found the end of the line")
+                }
+                Some(token) => Diagnostic::error(message)
+                    .with_label(token.span, format!("found `{}`", token.lexeme)),
+                None => Diagnostic::error(message)
+                    .with_label(self.current_span(), "unexpected end of file"),
+            };
+
+            self.diagnostics.push(diagnostic);
+            self.panic_mode = true;
+        }
+
+        None
+    }
+
+    /// Reports an error that does not affect parsing of the code after it.
+    fn error(&mut self, diagnostic: Diagnostic) {
+        if !self.panic_mode {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    /// Reports a likely mistake that does not stop the script from compiling.
+    fn warning(&mut self, diagnostic: Diagnostic) {
+        if !self.panic_mode {
+            self.warnings.push(diagnostic);
+        }
+    }
+
+    /// Warnings found by the last call to `parse`.
+    pub fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    /// Whether the parsed script has a chain of more than one command, or
+    /// reads the statuses of one.
+    pub fn has_pipelines(&self) -> bool {
+        self.pipelines
+    }
+
+    /// Whether the parsed script has a parallel for loop.
+    pub fn has_parallels(&self) -> bool {
+        self.parallels > 0
+    }
+
+    /// Whether the parsed script has a `defer` block.
+    pub fn has_defers(&self) -> bool {
+        self.defers > 0
+    }
+
+    fn current_span(&self) -> Span {
+        match self.get_token() {
+            Some(token) => token.span,
+            None => self.tokens.last().map_or_else(Span::default, |token| Span {
+                start: token.span.end,
+                end: token.span.end,
+                line: token.span.line,
+                column: token.span.column + token.lexeme.chars().count() + 1,
+            }),
+        }
+    }
+
+    /// Span from the token at `start` to the last consumed token.
+    fn span_since(&self, start: usize) -> Span {
+        let first = self
+            .tokens
+            .get(start)
+            .map_or_else(Span::default, |token| token.span);
+
+        match self
+            .index
+            .checked_sub(1)
+            .and_then(|last| self.tokens.get(last))
+        {
+            Some(last) if self.index > start => Span {
+                end: last.span.end,
+                ..first
+            },
+            _ => first,
+        }
+    }
+
+    /// Reports `expression`, which started at the token `start`, when it is
+    /// a float used where bash only accepts integers.
+    fn expect_integer(&mut self, expression: &Expression, start: usize) {
+        if self.is_float(expression) {
+            let span = self.span_since(start);
+            self.error(
+                Diagnostic::error("Expected an integer, found a float")
+                    .with_label(span, "floats cannot be used here"),
+            );
+        }
+    }
+
+    fn expect_token(&mut self, kind: Kind, message: &str) -> Option<Token> {
+        match self.consume_token(kind) {
+            Some(token) => Some(token),
+            None => self.syntax_error(message),
+        }
+    }
+
+    /// Runs `matcher`, reporting a syntax error with `message` when it does not match.
+    fn require<T>(
+        &mut self,
+        matcher: impl FnOnce(&mut Self) -> Option<T>,
+        message: &str,
+    ) -> Option<T> {
+        match matcher(self) {
+            Some(value) => Some(value),
+            None => self.syntax_error(message),
+        }
+    }
+
     fn get_token(&self) -> Option<&Token> {
         self.tokens.get(self.index)
     }
@@ -83,6 +543,12 @@ impl Parser {
         }
     }
 
+    fn consume_any_token(&mut self) -> Token {
+        let token = self.tokens[self.index].clone();
+        self.index += 1;
+        token
+    }
+
     fn consume_token_of_multiple_kinds(&mut self, valid_kinds: &[Kind]) -> Option<Token> {
         for kind in valid_kinds {
             if let Some(token) = self.consume_token(*kind) {
@@ -96,6 +562,7 @@ impl Parser {
     fn consume_variable_identifier(&mut self) -> Option<Token> {
         if self.match_token(Kind::Identifier).is_some()
             && self.peek_token(Kind::LeftParen).is_none()
+            && self.peek_token(Kind::LeftBracket).is_none()
         {
             Some(self.consume_token(Kind::Identifier)?)
         } else {
@@ -106,257 +573,1772 @@ impl Parser {
     fn match_evaluable_expression(&mut self) -> Option<Expression> {
         if let Some(expression) = self.match_arithmetic_expr() {
             Some(expression)
-        } else if let Some(token) = self.consume_token(Kind::String) {
+        } else if let Some(expression) = self.match_interpolated_string() {
+            Some(expression)
+        } else if let Some(token) =
+            self.consume_token_of_multiple_kinds(&[Kind::String, Kind::Regex, Kind::Boolean])
+        {
             Some(Expression::Value(ValueExpr { value: token }))
+        } else if let Some(expression) = self.match_array() {
+            Some(expression)
         } else {
-            self.match_fn_call()
+            self.match_map()
         }
     }
 
     fn match_value(&mut self) -> Option<Expression> {
-        if let Some(token) = self.consume_token(Kind::Number) {
-            Some(Expression::Value(ValueExpr { value: token }))
-        } else {
+        if let Some(token) = self.consume_token(Kind::Status) {
             Some(Expression::Value(ValueExpr {
-                value: self.consume_variable_identifier()?,
+                value: self.status_of_condition(token),
             }))
-        }
-    }
-
-    fn match_arithmetic_expr(&mut self) -> Option<Expression> {
-        let lhs = self.match_value().or_else(|| {
-            self.consume_token(Kind::LeftParen)?;
-
-            match self.match_arithmetic_expr()? {
-                Expression::Value(value) => Some(Expression::Value(value)),
-                expression => {
-                    self.consume_token(Kind::RightParen).unwrap();
-                    Some(Expression::Parenthesis(ParenthesisExpr {
-                        value: Box::new(expression),
-                    }))
-                }
+        } else if let Some(token) =
+            self.consume_token_of_multiple_kinds(&[Kind::Number, Kind::Float])
+        {
+            Some(Expression::Value(ValueExpr { value: token }))
+        } else if let Some(token) = self.consume_token(Kind::Statuses) {
+            self.pipelines = true;
+            if let Some(chain) = self.unsaved_statuses {
+                self.error(
+                    Diagnostic::error("statuses is not set by a chain whose output is a value")
+                        .with_label(token.span, "read after the chain")
+                        .with_secondary_label(chain, "runs in a subshell")
+                        .with_note("run the chain as a statement or a condition of its own"),
+                );
             }
-        })?;
+            let name = Token {
+                span: token.span,
+                ..Token::new("__statuses", Kind::Identifier)
+            };
 
-        let operator = self.consume_token_of_multiple_kinds(&[
-            Kind::Plus,
-            Kind::Minus,
-            Kind::Asterisk,
-            Kind::Slash,
-        ]);
+            if self.consume_token(Kind::LeftBracket).is_some() {
+                self.match_index_of(name)
+            } else {
+                Some(Expression::ArrayRef(ArrayRefExpr { name }))
+            }
+        } else if self.match_token(Kind::Identifier).is_some()
+            && self.peek_token(Kind::LeftBracket).is_some()
+        {
+            self.match_index()
+        } else {
+            let value = self.consume_variable_identifier()?;
 
-        if operator.is_none() {
-            return Some(lhs);
+            match self.variables.get(&value.lexeme) {
+                Some(Type::Array) => Some(Expression::ArrayRef(ArrayRefExpr { name: value })),
+                Some(Type::Map) => Some(Expression::MapRef(MapRefExpr { name: value })),
+                _ => Some(Expression::Value(ValueExpr { value })),
+            }
         }
+    }
 
-        let operator = operator.unwrap();
+    fn match_builtin_call(&self, names: &[&str]) -> bool {
+        match self.match_token(Kind::Identifier) {
+            Some(token) => {
+                names.contains(&token.lexeme.as_str())
+                    && self.peek_token(Kind::LeftParen).is_some()
+                    && !self.functions.contains_key(&token.lexeme)
+            }
+            None => false,
+        }
+    }
 
-        match self.match_arithmetic_expr() {
-            Some(rhs) => Some(Expression::Arithmetic(ArithmeticExpr {
-                lhs: Box::new(lhs),
-                operator,
-                rhs: Box::new(rhs),
-            })),
-            None => panic!(
-                "Syntax error: Expected numeric literal or variable identifier after operator {}",
-                operator.write()
-            ),
+    fn match_user_function_call(&self) -> bool {
+        match self.match_token(Kind::Identifier) {
+            Some(token) => {
+                self.functions.contains_key(&token.lexeme)
+                    && self.peek_token(Kind::LeftParen).is_some()
+            }
+            None => false,
         }
     }
 
-    fn match_conditional_expr(&mut self) -> Option<Expression> {
-        let lhs = self.match_arithmetic_expr().unwrap();
+    fn match_array(&mut self) -> Option<Expression> {
+        self.consume_token(Kind::LeftBracket)?;
 
-        let operator = self
-            .consume_token_of_multiple_kinds(&[
-                Kind::Less,
-                Kind::LessEqual,
-                Kind::Greater,
-                Kind::GreaterEqual,
-                Kind::EqualEqual,
-            ])
-            .expect("Expected operator");
+        let elements = self.match_fn_arguments();
 
-        let rhs = self.match_arithmetic_expr().unwrap();
+        self.expect_token(Kind::RightBracket, "Expected ] after array elements")?;
 
-        Some(Expression::Condition(ConditionExpr {
-            lhs: Box::new(lhs),
-            operator,
-            rhs: Box::new(rhs),
-        }))
+        Some(Expression::Array(ArrayExpr { elements }))
     }
 
-    fn match_statement_body(&mut self) -> Vec<Expression> {
-        self.consume_token(Kind::LeftBracket).expect("Expected {");
+    fn match_map(&mut self) -> Option<Expression> {
+        self.consume_token(Kind::LeftBrace)?;
 
-        let mut body = Vec::new();
+        let mut entries = Vec::new();
 
-        loop {
-            match self.get_token().unwrap().kind {
-                Kind::RightBracket => break,
-                Kind::Comment => self.skip_token(),
-                _ => body.push(match self.seek_expression() {
-                    Some(expression) => expression,
-                    None => break,
-                }),
-            }
-        }
+        // Entries can be on lines of their own
+        self.skip_separators();
 
-        self.consume_token(Kind::RightBracket).expect("Expected }");
+        while self.match_token(Kind::RightBrace).is_none() {
+            let key = self.require(Self::match_evaluable_expression, "Expected map key")?;
 
-        body
-    }
+            self.expect_token(Kind::Colon, "Expected : after map key")?;
 
-    fn match_if_statement(&mut self) -> Option<Expression> {
-        self.consume_token(Kind::If)?;
+            let value = self.require(
+                Self::match_evaluable_expression,
+                "Expected map value after :",
+            )?;
 
-        self.consume_token(Kind::LeftParen)
-            .expect("Expected ( after if keyword");
+            entries.push((key, value));
+
+            if self.consume_token(Kind::Coma).is_none() {
+                break;
+            }
+            self.skip_separators();
+        }
 
-        let condition = self.match_conditional_expr().unwrap();
+        self.skip_separators();
 
-        self.consume_token(Kind::RightParen)
-            .expect("Expected ( after if keyword");
+        self.expect_token(Kind::RightBrace, "Expected } after map entries")?;
 
-        let body = self.match_statement_body();
+        Some(Expression::Map(MapExpr { entries }))
+    }
 
-        let branching = self.match_else_if_statement().map(Box::new);
+    fn match_index(&mut self) -> Option<Expression> {
+        let name = self.consume_token(Kind::Identifier)?;
+        self.consume_token(Kind::LeftBracket)?;
 
-        Some(Expression::IfStatement(IfStatementExpr {
-            condition: Box::new(condition),
-            body,
-            branching,
-        }))
+        self.match_index_of(name)
     }
 
-    fn match_else_if_statement(&mut self) -> Option<Expression> {
-        self.consume_token(Kind::Else)?;
+    /// Parses the index or slice range after the `[` following `name`.
+    fn match_index_of(&mut self, name: Token) -> Option<Expression> {
+        let is_map = self.variables.get(&name.lexeme) == Some(&Type::Map);
 
-        if self.match_token(Kind::If).is_some() {
-            match self.match_if_statement().unwrap() {
-                Expression::IfStatement(IfStatementExpr {
-                    condition,
-                    body,
-                    branching,
-                }) => Some(Expression::ElifStatement(ElifStatementExpr {
-                    condition,
-                    body,
-                    branching,
-                })),
-                _ => todo!(),
-            }
+        let start_index = self.index;
+        let start = if is_map {
+            self.match_evaluable_expression().map(Box::new)
         } else {
-            let body = self.match_statement_body();
-            Some(Expression::ElseStatement(ElseStatementExpr { body }))
+            self.match_arithmetic_expr().map(Box::new)
+        };
+
+        if let (Some(start), false) = (&start, is_map) {
+            self.expect_integer(start, start_index);
         }
-    }
 
-    fn match_while_statement(&mut self) -> Option<Expression> {
-        self.consume_token(Kind::While)?;
+        if let Some(range) = self.consume_token(Kind::Range) {
+            if is_map {
+                self.error(
+                    Diagnostic::error(format!("Map \"{}\" cannot be sliced", name.lexeme))
+                        .with_label(range.span, "slicing is only supported for arrays"),
+                );
+            }
 
-        self.consume_token(Kind::LeftParen)
-            .expect("Expected ( after for keyword");
+            let end_index = self.index;
+            let end = self.match_arithmetic_expr().map(Box::new);
 
-        let condition = self.match_conditional_expr().unwrap();
+            if let Some(end) = &end {
+                self.expect_integer(end, end_index);
+            }
 
-        self.consume_token(Kind::RightParen).expect("Expected )");
+            self.expect_token(Kind::RightBracket, "Expected ] after slice range")?;
 
-        let body = self.match_statement_body();
+            return Some(Expression::Slice(SliceExpr { name, start, end }));
+        }
 
-        Some(Expression::WhileStatement(WhileStatementExpr {
-            condition: Box::new(condition),
-            body,
-        }))
-    }
+        let Some(index) = start else {
+            return self.syntax_error("Expected index expression after [");
+        };
 
-    fn match_var_assignment(&mut self) -> Option<Expression> {
-        let name = self.consume_token(Kind::Identifier)?;
-        self.consume_token(Kind::Equal);
+        self.expect_token(Kind::RightBracket, "Expected ] after index expression")?;
 
-        let value = self
-            .match_evaluable_expression()
-            .expect("Expected evaluable expression after let _ =");
+        Some(Expression::Index(IndexExpr { name, index }))
+    }
 
-        Some(Expression::VarAssignment(VarAssignmentExpr {
-            name,
-            value: Box::new(value),
-        }))
+    fn match_arithmetic_expr(&mut self) -> Option<Expression> {
+        self.match_binary_expr(0)
     }
 
-    fn match_var_declaration(&mut self) -> Option<Expression> {
-        self.consume_token(Kind::Let);
-        let assignment = self.match_var_assignment().unwrap();
+    /// Precedence climbing over binary operators: only operators binding at
+    /// least as tight as `min_precedence` are consumed at this level.
+    fn match_binary_expr(&mut self, min_precedence: u8) -> Option<Expression> {
+        let mut lhs = self.match_unary_expr()?;
+
+        while let Some(precedence) = self
+            .get_token()
+            .and_then(|token| token.kind.precedence())
+            .filter(|precedence| *precedence >= min_precedence)
+        {
+            let operator = self.consume_any_token();
+
+            let rhs_precedence = if operator.kind.is_right_associative() {
+                precedence
+            } else {
+                precedence + 1
+            };
+
+            let Some(rhs) = self.match_binary_expr(rhs_precedence) else {
+                return self.missing_operand(&operator);
+            };
 
-        match assignment {
-            Expression::VarAssignment(VarAssignmentExpr { name, value }) => {
-                Some(Expression::VarDeclaration(VarDeclarationExpr {
-                    name,
-                    value,
-                }))
+            let float = self.is_float(&lhs) || self.is_float(&rhs);
+            if float {
+                self.expect_integer_operator(&operator);
             }
-            _ => panic!(),
+
+            lhs = Expression::Arithmetic(ArithmeticExpr {
+                lhs: Box::new(lhs),
+                operator,
+                rhs: Box::new(rhs),
+                float,
+            });
         }
+
+        Some(lhs)
     }
 
-    fn match_identifier(&mut self) -> Option<Expression> {
-        if self.peek_token(Kind::Equal).is_some() {
-            self.match_var_assignment()
-        } else {
-            self.match_fn_chain()
+    fn match_unary_expr(&mut self) -> Option<Expression> {
+        let Some(operator) = self.consume_token_of_multiple_kinds(&[Kind::Minus, Kind::Tilde])
+        else {
+            return self.match_primary_expr();
+        };
+
+        let Some(operand) = self.match_binary_expr(UNARY_PRECEDENCE) else {
+            return self.missing_operand(&operator);
+        };
+
+        // A minus before a number is part of the literal
+        if let Expression::Value(ValueExpr { value }) = &operand {
+            if operator.kind == Kind::Minus && matches!(value.kind, Kind::Number | Kind::Float) {
+                let lexeme = match value.lexeme.strip_prefix('-') {
+                    Some(positive) => positive.to_string(),
+                    None => format!("-{}", value.lexeme),
+                };
+
+                return Some(Expression::Value(ValueExpr {
+                    value: Token {
+                        lexeme,
+                        kind: value.kind,
+                        span: Span {
+                            end: value.span.end,
+                            ..operator.span
+                        },
+                    },
+                }));
+            }
         }
+
+        let float = self.is_float(&operand);
+        if float {
+            self.expect_integer_operator(&operator);
+        }
+
+        Some(Expression::Unary(UnaryExpr {
+            operator,
+            operand: Box::new(operand),
+            float,
+        }))
     }
 
-    fn match_fn_chain(&mut self) -> Option<Expression> {
-        let mut invocations = Vec::new();
+    fn is_float(&self, expression: &Expression) -> bool {
+        types::infer(expression, &self.variables) == Type::Float
+    }
 
-        loop {
-            invocations.push(self.match_fn_call()?);
-            if self.consume_token(Kind::Period).is_none() {
-                break;
-            }
+    /// Reports a bitwise `operator` applied to a float.
+    fn expect_integer_operator(&mut self, operator: &Token) {
+        if matches!(
+            operator.kind,
+            Kind::Ampersand
+                | Kind::Pipe
+                | Kind::Caret
+                | Kind::Tilde
+                | Kind::ShiftLeft
+                | Kind::ShiftRight
+        ) {
+            self.error(
+                Diagnostic::error("Bitwise operators only work on integers")
+                    .with_label(operator.span, "applied to a float"),
+            );
         }
+    }
+
+    fn missing_operand<T>(&mut self, operator: &Token) -> Option<T> {
+        let span = self.current_span();
+        let found = self.get_token().map_or_else(
+            || "end of file".into(),
+            |token| format!("`{}`", token.lexeme),
+        );
+
+        self.error(
+            Diagnostic::error(format!(
+                "Expected numeric literal or variable identifier after operator {}",
+                operator.lexeme
+            ))
+            .with_label(span, format!("found {found}"))
+            .with_secondary_label(operator.span, "operator expects a value on this side"),
+        );
+        self.panic_mode = true;
 
-        Some(Expression::FnChain(FnChain { invocations }))
+        None
     }
 
-    fn match_fn_call(&mut self) -> Option<Expression> {
-        let name = self.consume_token_of_multiple_kinds(&[Kind::Identifier, Kind::Command])?;
+    fn match_primary_expr(&mut self) -> Option<Expression> {
+        if self.consume_token(Kind::LeftParen).is_some() {
+            let expression =
+                self.require(Self::match_arithmetic_expr, "Expected expression after (")?;
 
-        self.consume_token(Kind::LeftParen)
-            .expect("Missing ( after identifier ");
+            self.expect_token(Kind::RightParen, "Expected ) after expression")?;
 
-        let args = self.match_fn_arguments();
+            return Some(expression);
+        }
 
-        self.consume_token(Kind::RightParen)
-            .expect("Missing ) after parameters list");
+        if let Some(value) = self.match_value() {
+            return Some(value);
+        }
 
-        Some(Expression::FnCall(FnCall {
-            name: name.clone(),
-            args,
-            command: matches!(name.kind, Kind::Command),
-        }))
-    }
+        if self.match_builtin_call(WAITS) {
+            let name = self.get_token()?;
+            let span = name.span;
+            self.error(
+                Diagnostic::error(format!(
+                    "The exit status of {}() can only be stored in a variable",
+                    name.lexeme
+                ))
+                .with_label(span, "used as a value")
+                .with_note("write let code = wait(job) and use code instead"),
+            );
+        }
 
-    fn match_fn_arguments(&mut self) -> Vec<Expression> {
-        let mut args = Vec::new();
+        match self.match_fn_chain()? {
+            Expression::FnChain(FnChain {
+                mut invocations,
+                redirects,
+                lines: false,
+                ..
+            }) if invocations.len() == 1 && redirects.is_empty() => match invocations.pop()? {
+                Expression::FnCall(call) if call.user_defined => Some(self.value_of_call(call)),
+                invocation => Some(invocation),
+            },
+            chain => Some(chain),
+        }
+    }
 
-        loop {
-            if let Some(arg) =
-                self.consume_token_of_multiple_kinds(&[Kind::String, Kind::RawString, Kind::Option])
-            {
-                args.push(Expression::Value(ValueExpr { value: arg }));
-            } else {
-                match self.match_arithmetic_expr().or(self.match_fn_call()) {
-                    Some(expr) => args.push(expr),
-                    None => break,
-                }
-            }
+    /// Parses the condition of an if, while or do while statement. A
+    /// condition reading `status` reads the status saved before it instead.
+    fn match_condition(&mut self) -> Option<Expression> {
+        let outer = self.condition_status.replace(false);
+        let outer_calls = std::mem::take(&mut self.calls);
+        let condition = self.require(Self::match_conditional_expr, "Expected condition");
+        let calls = std::mem::replace(&mut self.calls, outer_calls);
+        let condition = condition.map(|condition| self.make_calls(calls, condition));
+        let reads_status = self.condition_status == Some(true);
+        self.condition_status = outer;
+
+        if reads_status {
+            Some(Expression::StatusCondition(StatusConditionExpr {
+                condition: Box::new(condition?),
+            }))
+        } else {
+            condition
+        }
+    }
 
-            if self.consume_token(Kind::Coma).is_none() {
-                break;
-            }
+    /// Makes `calls` to user functions right before `expression`, which uses
+    /// their values.
+    fn make_calls(&self, calls: Vec<(Token, FnCall)>, expression: Expression) -> Expression {
+        if calls.is_empty() {
+            expression
+        } else {
+            Expression::Called(CalledExpr {
+                calls,
+                expression: Box::new(expression),
+                local: self.in_function,
+            })
         }
+    }
 
-        args
+    /// Gives back the variable that the value of a call to a user function is
+    /// stored in, making the call before the statement or condition using it.
+    fn value_of_call(&mut self, call: FnCall) -> Expression {
+        self.anonymous_counters += 1;
+        let value = Token {
+            span: call.name.span,
+            ..Token::new(
+                format!("__call{}", self.anonymous_counters),
+                Kind::Identifier,
+            )
+        };
+
+        self.variables.insert(value.lexeme.clone(), Type::Unknown);
+        self.calls.push((value.clone(), call));
+
+        Expression::Value(ValueExpr { value })
+    }
+
+    /// Gives back the variable holding the status inside of a condition, or
+    /// `status` itself anywhere else.
+    fn status_of_condition(&mut self, status: Token) -> Token {
+        let Some(reads_status) = &mut self.condition_status else {
+            return status;
+        };
+
+        *reads_status = true;
+        self.variables.insert(STATUS_VARIABLE.into(), Type::Int);
+
+        Token {
+            span: status.span,
+            ..Token::new(STATUS_VARIABLE, Kind::Identifier)
+        }
+    }
+
+    fn match_conditional_expr(&mut self) -> Option<Expression> {
+        let mut lhs = self.match_and_condition()?;
+
+        while let Some(operator) = self.consume_token(Kind::Or) {
+            let rhs = self.require(Self::match_and_condition, "Expected condition after ||")?;
+
+            lhs = Expression::Logical(LogicalExpr {
+                lhs: Box::new(lhs),
+                operator,
+                rhs: Box::new(rhs),
+            });
+        }
+
+        Some(lhs)
+    }
+
+    fn match_and_condition(&mut self) -> Option<Expression> {
+        let mut lhs = self.match_unary_condition()?;
+
+        while let Some(operator) = self.consume_token(Kind::And) {
+            let rhs = self.require(Self::match_unary_condition, "Expected condition after &&")?;
+
+            lhs = Expression::Logical(LogicalExpr {
+                lhs: Box::new(lhs),
+                operator,
+                rhs: Box::new(rhs),
+            });
+        }
+
+        Some(lhs)
+    }
+
+    fn match_unary_condition(&mut self) -> Option<Expression> {
+        if self.consume_token(Kind::Not).is_some() {
+            let value = self.require(Self::match_unary_condition, "Expected condition after !")?;
+
+            return Some(Expression::Not(NotExpr {
+                value: Box::new(value),
+            }));
+        }
+
+        if self.match_token(Kind::LeftParen).is_some() && self.is_grouped_condition() {
+            self.consume_token(Kind::LeftParen);
+            let condition =
+                self.require(Self::match_conditional_expr, "Expected condition after (")?;
+            self.expect_token(Kind::RightParen, "Expected ) after grouped condition")?;
+            return Some(condition);
+        }
+
+        if self.match_builtin_call(&["has", "wait", "wait_all"]) {
+            return self.match_fn_call();
+        }
+
+        if self.match_user_function_call() {
+            return self.match_function_condition();
+        }
+
+        if self.match_token(Kind::Command).is_some() {
+            return self.match_condition_chain();
+        }
+
+        if let Some(value) = self.consume_token(Kind::Boolean) {
+            return Some(Expression::Boolean(BooleanExpr { value }));
+        }
+
+        self.match_comparison()
+    }
+
+    /// Parses a chain of commands as a condition, which is true when it succeeds.
+    fn match_condition_chain(&mut self) -> Option<Expression> {
+        let mut chain = self.match_fn_chain()?;
+
+        if let Expression::FnChain(chain) = &mut chain {
+            self.save_statuses(chain);
+        }
+
+        Some(chain)
+    }
+
+    /// Parses a call to a user function as a condition, which is true when the
+    /// function returns `true`.
+    fn match_function_condition(&mut self) -> Option<Expression> {
+        let chain = self.match_condition_chain()?;
+
+        if let Expression::FnChain(FnChain {
+            invocations,
+            redirects,
+            lines: false,
+            ..
+        }) = &chain
+        {
+            if let ([Expression::FnCall(call)], []) = (invocations.as_slice(), redirects.as_slice())
+            {
+                return Some(Expression::FunctionCondition(FunctionConditionExpr {
+                    call: call.clone(),
+                }));
+            }
+        }
+
+        Some(chain)
+    }
+
+    /// Checks whether the parenthesis at the current token groups conditions,
+    /// like `(a > 1 || b)`, rather than being a part of an arithmetic operand.
+    fn is_grouped_condition(&self) -> bool {
+        let mut depth = 0;
+
+        for token in &self.tokens[self.index..] {
+            match token.kind {
+                Kind::LeftParen => depth += 1,
+                Kind::RightParen => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return false;
+                    }
+                }
+                Kind::And
+                | Kind::Or
+                | Kind::Not
+                | Kind::Less
+                | Kind::LessEqual
+                | Kind::Greater
+                | Kind::GreaterEqual
+                | Kind::EqualEqual
+                | Kind::NotEqual
+                | Kind::Command
+                | Kind::Boolean
+                    if depth == 1 =>
+                {
+                    return true;
+                }
+                _ => {}
+            }
+        }
+
+        false
+    }
+
+    fn match_comparison(&mut self) -> Option<Expression> {
+        let lhs = self.match_operand()?;
+
+        let Some(operator) = self.consume_token_of_multiple_kinds(&[
+            Kind::Less,
+            Kind::LessEqual,
+            Kind::Greater,
+            Kind::GreaterEqual,
+            Kind::EqualEqual,
+            Kind::NotEqual,
+        ]) else {
+            return match lhs {
+                Expression::Value(ValueExpr { value }) if value.kind == Kind::Identifier => {
+                    Some(Expression::Boolean(BooleanExpr { value }))
+                }
+                _ => self.syntax_error("Expected comparison operator"),
+            };
+        };
+
+        let rhs = self.require(
+            Self::match_operand,
+            "Expected value after comparison operator",
+        )?;
+
+        let lhs_type = types::infer(&lhs, &self.variables);
+        let rhs_type = types::infer(&rhs, &self.variables);
+
+        let comparison = match types::resolve_comparison(lhs_type, rhs_type, operator.kind) {
+            Ok(comparison) => comparison,
+            Err(error) => {
+                self.error(
+                    Diagnostic::error(error)
+                        .with_label(operator.span, "operands of different types")
+                        .with_note(format!(
+                            "the left side is {lhs_type} and the right side is {rhs_type}"
+                        )),
+                );
+                types::Comparison::Numeric
+            }
+        };
+
+        Some(Expression::Condition(ConditionExpr {
+            lhs: Box::new(lhs),
+            operator,
+            rhs: Box::new(rhs),
+            comparison,
+        }))
+    }
+
+    fn match_operand(&mut self) -> Option<Expression> {
+        match self.consume_token_of_multiple_kinds(&[Kind::String, Kind::Boolean]) {
+            Some(token) => Some(Expression::Value(ValueExpr { value: token })),
+            None => self
+                .match_interpolated_string()
+                .or_else(|| self.match_arithmetic_expr()),
+        }
+    }
+
+    /// Matches a string with expressions interpolated into it, like `"sum: {a + b}"`.
+    fn match_interpolated_string(&mut self) -> Option<Expression> {
+        let start = self.consume_token(Kind::InterpolationStart)?;
+
+        let mut parts = vec![Expression::Value(ValueExpr {
+            value: Token::new(start.lexeme, Kind::String),
+        })];
+
+        loop {
+            let expression = self.require(
+                Self::match_operand,
+                "Expected expression inside of {} in a string",
+            )?;
+            self.check_declared(&expression);
+            parts.push(expression);
+
+            let text = match self.consume_token(Kind::InterpolationMiddle) {
+                Some(middle) => middle,
+                None => self.expect_token(
+                    Kind::InterpolationEnd,
+                    "Expected } after interpolated expression",
+                )?,
+            };
+            let end = text.kind == Kind::InterpolationEnd;
+
+            parts.push(Expression::Value(ValueExpr {
+                value: Token::new(text.lexeme, Kind::String),
+            }));
+
+            if end {
+                break;
+            }
+        }
+
+        parts.retain(|part| {
+            !matches!(part, Expression::Value(ValueExpr { value }) if value.kind == Kind::String && value.lexeme.is_empty())
+        });
+
+        Some(Expression::InterpolatedString(InterpolatedStringExpr {
+            parts,
+        }))
+    }
+
+    /// Warns about variables in `expression` that were never declared, which
+    /// are likely typos unless they are set by a command or the environment.
+    fn check_declared(&mut self, expression: &Expression) {
+        match expression {
+            Expression::Value(ValueExpr { value })
+                if value.kind == Kind::Identifier
+                    && !self.variables.contains_key(&value.lexeme) =>
+            {
+                self.warning(
+                    Diagnostic::warning(format!("Variable \"{}\" is not declared", value.lexeme))
+                        .with_label(value.span, "not declared with let")
+                        .with_note("it is empty unless it is set by a command or the environment"),
+                );
+            }
+            Expression::Arithmetic(ArithmeticExpr { lhs, rhs, .. }) => {
+                self.check_declared(lhs);
+                self.check_declared(rhs);
+            }
+            Expression::Unary(UnaryExpr { operand, .. }) => self.check_declared(operand),
+            _ => {}
+        }
+    }
+
+    fn match_statement_body(&mut self) -> Option<Vec<Expression>> {
+        if self.consume_token_across_lines(Kind::LeftBrace).is_none() {
+            return self.syntax_error("Expected {");
+        }
+
+        let mut body = Vec::new();
+
+        loop {
+            self.skip_separators();
+            if self.match_token(Kind::RightBrace).is_some() {
+                break;
+            }
+
+            match self.match_statement() {
+                Some(expression) => body.push(expression),
+                None => break,
+            }
+        }
+
+        self.expect_token(Kind::RightBrace, "Expected }")?;
+
+        Some(body)
+    }
+
+    fn match_if_statement(&mut self) -> Option<Expression> {
+        self.consume_token(Kind::If)?;
+
+        self.expect_token(Kind::LeftParen, "Expected ( after if keyword")?;
+
+        let condition = self.match_condition()?;
+
+        self.expect_token(Kind::RightParen, "Expected ) after condition")?;
+
+        let body = self.match_statement_body()?;
+
+        let branching = self.match_else_if_statement().map(Box::new);
+
+        Some(Expression::IfStatement(IfStatementExpr {
+            condition: Box::new(condition),
+            body,
+            branching,
+        }))
+    }
+
+    /// Parses the `else` after the block of an if statement. Comments between
+    /// the block and the `else` are kept at the start of the else block.
+    fn match_else_if_statement(&mut self) -> Option<Expression> {
+        let start = self.index;
+
+        let mut comments = Vec::new();
+        while let Some(comment) = self.consume_token_across_lines(Kind::Comment) {
+            comments.push(comment);
+        }
+
+        if self.consume_token_across_lines(Kind::Else).is_none() {
+            self.index = start;
+            return None;
+        }
+
+        let comments = if comments.is_empty() {
+            None
+        } else {
+            Some(Expression::Commented(CommentedExpr {
+                comments,
+                statement: None,
+            }))
+        };
+
+        if self.match_token(Kind::If).is_some() {
+            let Expression::IfStatement(IfStatementExpr {
+                condition,
+                mut body,
+                branching,
+            }) = self.match_if_statement()?
+            else {
+                unreachable!("match_if_statement only returns if statements");
+            };
+
+            body.splice(0..0, comments);
+
+            Some(Expression::ElifStatement(ElifStatementExpr {
+                condition,
+                body,
+                branching,
+            }))
+        } else {
+            let mut body = self.match_statement_body()?;
+            body.splice(0..0, comments);
+            Some(Expression::ElseStatement(ElseStatementExpr { body }))
+        }
+    }
+
+    fn match_while_statement(&mut self) -> Option<Expression> {
+        self.consume_token(Kind::While)?;
+
+        self.expect_token(Kind::LeftParen, "Expected ( after while keyword")?;
+
+        let condition = self.match_condition()?;
+
+        self.expect_token(Kind::RightParen, "Expected ) after condition")?;
+
+        let body = self.match_loop_body()?;
+
+        Some(Expression::WhileStatement(WhileStatementExpr {
+            condition: Box::new(condition),
+            body,
+        }))
+    }
+
+    fn match_loop_statement(&mut self) -> Option<Expression> {
+        self.consume_token(Kind::Loop)?;
+
+        let body = self.match_loop_body()?;
+
+        Some(Expression::Loop(LoopExpr { body }))
+    }
+
+    fn match_do_while_statement(&mut self) -> Option<Expression> {
+        let keyword = self.consume_token(Kind::Do)?;
+
+        let body = self.match_loop_body()?;
+
+        if self.consume_token_across_lines(Kind::While).is_none() {
+            return self.syntax_error("Expected while after the body of do");
+        }
+
+        self.expect_token(Kind::LeftParen, "Expected ( after while keyword")?;
+
+        let condition = self.match_condition()?;
+
+        self.expect_token(Kind::RightParen, "Expected ) after condition")?;
+
+        self.anonymous_counters += 1;
+        let first = Token {
+            span: keyword.span,
+            ..Token::new(format!("__do{}", self.anonymous_counters), Kind::Identifier)
+        };
+
+        Some(Expression::DoWhile(DoWhileExpr {
+            condition: Box::new(condition),
+            body,
+            first,
+        }))
+    }
+
+    /// Parses a loop preceded by `label:`, which `break` and `continue` in
+    /// nested loops can refer to.
+    fn match_labeled_loop(&mut self) -> Option<Expression> {
+        let label = self.consume_token(Kind::Identifier)?;
+        self.consume_token(Kind::Colon)?;
+
+        if let Some(outer) = self
+            .loops
+            .iter()
+            .flatten()
+            .find(|outer| outer.lexeme == label.lexeme)
+        {
+            let outer = outer.span;
+            self.error(
+                Diagnostic::error(format!("Loop label \"{}\" is already used", label.lexeme))
+                    .with_label(label.span, "used again here")
+                    .with_secondary_label(outer, "by this enclosing loop"),
+            );
+        }
+
+        self.loop_label = Some(label);
+
+        let loop_statement = match self.get_token().map(|token| token.kind) {
+            Some(Kind::While) => self.match_while_statement(),
+            Some(Kind::For) => self.match_for_statement(),
+            Some(Kind::Loop) => self.match_loop_statement(),
+            Some(Kind::Do) => self.match_do_while_statement(),
+            _ => self.syntax_error("Expected a loop after label"),
+        };
+
+        self.loop_label = None;
+        loop_statement
+    }
+
+    /// Parses the body of a loop, in which `break` and `continue` can be used.
+    fn match_loop_body(&mut self) -> Option<Vec<Expression>> {
+        self.loops.push(self.loop_label.take());
+        let body = self.match_statement_body();
+        self.loops.pop();
+        body
+    }
+
+    fn match_loop_control(&mut self) -> Option<Expression> {
+        let keyword = self.consume_token_of_multiple_kinds(&[Kind::Break, Kind::Continue])?;
+        let label = self.consume_token(Kind::Identifier);
+
+        if self.loops.is_empty() {
+            self.error(
+                Diagnostic::error(format!("Cannot use {} outside of a loop", keyword.lexeme))
+                    .with_label(keyword.span, "not inside of a loop"),
+            );
+            return Some(Expression::LoopControl(LoopControlExpr {
+                keyword,
+                depth: 1,
+            }));
+        }
+
+        let Some(label) = label else {
+            return Some(Expression::LoopControl(LoopControlExpr {
+                keyword,
+                depth: 1,
+            }));
+        };
+
+        let position = self.loops.iter().rposition(|outer| {
+            outer
+                .as_ref()
+                .is_some_and(|outer| outer.lexeme == label.lexeme)
+        });
+
+        let Some(position) = position else {
+            self.error(
+                Diagnostic::error(format!("Unknown loop label \"{}\"", label.lexeme))
+                    .with_label(label.span, "no enclosing loop has this label"),
+            );
+            return Some(Expression::LoopControl(LoopControlExpr {
+                keyword,
+                depth: 1,
+            }));
+        };
+
+        let depth = self.loops.len() - position;
+
+        Some(Expression::LoopControl(LoopControlExpr { keyword, depth }))
+    }
+
+    fn match_for_statement(&mut self) -> Option<Expression> {
+        self.match_for_loop(Self::match_loop_body)
+            .map(Expression::ForStatement)
+    }
+
+    /// Parses a for loop, whose body is parsed with `match_body`.
+    fn match_for_loop(
+        &mut self,
+        match_body: fn(&mut Self) -> Option<Vec<Expression>>,
+    ) -> Option<ForStatementExpr> {
+        let keyword = self.consume_token(Kind::For)?;
+
+        let mut value_variable = None;
+
+        let variable =
+            if self.peek_token(Kind::In).is_some() || self.peek_token(Kind::Coma).is_some() {
+                let variable = self.expect_token(
+                    Kind::Identifier,
+                    "Expected loop variable identifier before in keyword",
+                )?;
+
+                if self.consume_token(Kind::Coma).is_some() {
+                    value_variable = Some(self.expect_token(
+                        Kind::Identifier,
+                        "Expected value variable identifier after ,",
+                    )?);
+                }
+
+                self.expect_token(Kind::In, "Expected in keyword after loop variables")?;
+                variable
+            } else {
+                self.anonymous_counters += 1;
+                Token {
+                    span: keyword.span,
+                    ..Token::new(format!("__i{}", self.anonymous_counters), Kind::Identifier)
+                }
+            };
+
+        let iterable = self.require(
+            Self::match_iterable,
+            "Expected range, array or command after for keyword",
+        )?;
+
+        if let Some(value_variable) = &value_variable {
+            if !matches!(iterable, Expression::ArrayRef(_) | Expression::MapRef(_)) {
+                self.error(
+                    Diagnostic::error(
+                        "Only arrays and maps can be iterated with two loop variables",
+                    )
+                    .with_label(value_variable.span, "second loop variable"),
+                );
+            }
+        }
+
+        let variable_type = match (&iterable, &value_variable) {
+            (Expression::Range(_), _) | (Expression::ArrayRef(_), Some(_)) => Type::Int,
+            (Expression::MapRef(_), _) => Type::String,
+            _ => Type::Unknown,
+        };
+        self.variables
+            .insert(variable.lexeme.clone(), variable_type);
+
+        if let Some(value_variable) = &value_variable {
+            self.variables
+                .insert(value_variable.lexeme.clone(), Type::Unknown);
+        }
+
+        let body = match_body(self)?;
+
+        Some(ForStatementExpr {
+            variable,
+            value_variable,
+            iterable: Box::new(iterable),
+            body,
+        })
+    }
+
+    /// Parses `parallel(max: n) for ...`, which runs every iteration of the
+    /// loop as a background job.
+    fn match_parallel_statement(&mut self) -> Option<Expression> {
+        let keyword = self.consume_token(Kind::Parallel)?;
+
+        self.expect_token(Kind::LeftParen, "Expected ( after parallel keyword")?;
+        let option = self.expect_token(Kind::Identifier, "Expected max: after (")?;
+        if option.lexeme != "max" {
+            self.error(
+                Diagnostic::error(format!("Unknown option \"{}\" of parallel", option.lexeme))
+                    .with_label(option.span, "expected max"),
+            );
+        }
+        self.expect_token(Kind::Colon, "Expected : after max")?;
+
+        let start = self.index;
+        let max = self.require(
+            Self::match_arithmetic_expr,
+            "Expected the maximum number of jobs after max:",
+        )?;
+        self.expect_integer(&max, start);
+
+        self.expect_token(
+            Kind::RightParen,
+            "Expected ) after the maximum number of jobs",
+        )?;
+
+        if self.match_token(Kind::For).is_none() {
+            return self.syntax_error("Expected a for loop after parallel()");
+        }
+
+        let each = self.match_for_loop(Self::match_detached_body)?;
+
+        self.anonymous_counters += 1;
+        let jobs = Token {
+            span: keyword.span,
+            ..Token::new(
+                format!("__jobs{}", self.anonymous_counters),
+                Kind::Identifier,
+            )
+        };
+
+        self.parallels += 1;
+
+        Some(Expression::Parallel(ParallelExpr {
+            max: Box::new(max),
+            each,
+            jobs,
+        }))
+    }
+
+    /// Parses `spawn { ... }` or `spawn command!()`, which run in the background.
+    fn match_spawn(&mut self) -> Option<Expression> {
+        self.consume_token(Kind::Spawn)?;
+
+        let body = if self.match_token(Kind::LeftBrace).is_some() {
+            self.match_detached_body()?
+        } else {
+            vec![self.require(
+                Self::match_fn_chain,
+                "Expected a block or a command after spawn",
+            )?]
+        };
+
+        Some(Expression::Spawn(SpawnExpr { body }))
+    }
+
+    /// Parses `defer { ... }`, whose block runs when the function around it
+    /// returns, or when the script exits.
+    fn match_defer_statement(&mut self) -> Option<Expression> {
+        self.consume_token(Kind::Defer)?;
+
+        let body = self.match_detached_body()?;
+
+        self.defers += 1;
+        self.anonymous_counters += 1;
+
+        Some(Expression::Defer(DeferExpr {
+            body,
+            id: self.anonymous_counters,
+        }))
+    }
+
+    /// Parses a block that runs apart from the statements around it, like a
+    /// background job or a deferred block, so the loops around it cannot be
+    /// controlled from inside of it.
+    fn match_detached_body(&mut self) -> Option<Vec<Expression>> {
+        let loops = std::mem::take(&mut self.loops);
+        let body = self.match_statement_body();
+        self.loops = loops;
+        body
+    }
+
+    fn match_iterable(&mut self) -> Option<Expression> {
+        if let Some(array) = self.match_array() {
+            return Some(array);
+        }
+
+        let start_index = self.index;
+        let Some(start) = self.match_arithmetic_expr() else {
+            return self.match_fn_chain();
+        };
+
+        if self.consume_token(Kind::Range).is_none() {
+            return Some(start);
+        }
+
+        self.expect_integer(&start, start_index);
+
+        let end_index = self.index;
+        let end = self.require(
+            Self::match_arithmetic_expr,
+            "Expected numeric literal or variable identifier after ..",
+        )?;
+
+        self.expect_integer(&end, end_index);
+
+        Some(Expression::Range(RangeExpr {
+            start: Box::new(start),
+            end: Box::new(end),
+        }))
+    }
+
+    fn match_function_declaration(&mut self) -> Option<Expression> {
+        let keyword = self.consume_token(Kind::Function)?;
+
+        if self.in_function {
+            self.error(
+                Diagnostic::error("Functions cannot be declared inside other functions")
+                    .with_label(keyword.span, "nested function declaration"),
+            );
+        }
+
+        let name = self.expect_token(
+            Kind::Identifier,
+            "Expected function name after function keyword",
+        )?;
+
+        self.expect_token(Kind::LeftParen, "Expected ( after function name")?;
+
+        let mut params = Vec::new();
+        while let Some(param) = self.consume_token(Kind::Identifier) {
+            self.variables.insert(param.lexeme.clone(), Type::Unknown);
+            params.push(param);
+            if self.consume_token(Kind::Coma).is_none() {
+                break;
+            }
+        }
+
+        self.expect_token(Kind::RightParen, "Expected ) after parameters list")?;
+
+        // Loops around the declaration cannot be exited from inside of it
+        let loops = std::mem::take(&mut self.loops);
+        let defers = self.defers;
+        self.in_function = true;
+        let body = self.match_statement_body();
+        self.in_function = false;
+        self.loops = loops;
+        let body = body?;
+
+        Some(Expression::FunctionDecl(FunctionDeclExpr {
+            name,
+            params,
+            body,
+            defers: self.defers > defers,
+        }))
+    }
+
+    fn match_return_statement(&mut self) -> Option<Expression> {
+        let keyword = self.consume_token(Kind::Return)?;
+
+        if !self.in_function {
+            self.error(
+                Diagnostic::error("return can only be used inside of a function")
+                    .with_label(keyword.span, "outside of a function"),
+            );
+        }
+
+        let value = if self.match_token(Kind::RightBrace).is_some() {
+            None
+        } else {
+            self.match_evaluable_expression().map(Box::new)
+        };
+
+        Some(Expression::Return(ReturnExpr { value }))
+    }
+
+    fn match_var_assignment(&mut self) -> Option<Expression> {
+        let name = self.expect_token(Kind::Identifier, "Expected variable name")?;
+        self.expect_token(Kind::Equal, "Expected = after variable name")?;
+
+        let value = if let Some(run) = self.match_run() {
+            run
+        } else if self.match_token(Kind::Spawn).is_some() {
+            self.match_spawn()?
+        } else if self.match_builtin_call(WAITS) {
+            self.match_fn_call()?
+        } else {
+            self.require(Self::match_evaluable_expression, "Expected value after =")?
+        };
+
+        if let Expression::MapRef(MapRefExpr { name: map }) = &value {
+            self.error(
+                Diagnostic::error(format!("Map \"{}\" cannot be copied", map.lexeme))
+                    .with_label(map.span, "maps can only be declared with a literal"),
+            );
+        }
+
+        self.variables
+            .insert(name.lexeme.clone(), types::infer(&value, &self.variables));
+
+        Some(Expression::VarAssignment(VarAssignmentExpr {
+            name,
+            value: Box::new(value),
+        }))
+    }
+
+    fn match_var_declaration(&mut self) -> Option<Expression> {
+        self.consume_token(Kind::Let)?;
+
+        let Expression::VarAssignment(VarAssignmentExpr { name, value }) =
+            self.match_var_assignment()?
+        else {
+            unreachable!("match_var_assignment only returns assignments");
+        };
+
+        Some(Expression::VarDeclaration(VarDeclarationExpr {
+            name,
+            value,
+            local: self.in_function,
+        }))
+    }
+
+    fn match_index_assignment(&mut self) -> Option<Expression> {
+        let Expression::Index(IndexExpr { name, index }) = self.match_index()? else {
+            return self.syntax_error("Cannot assign to an array slice");
+        };
+
+        self.expect_token(Kind::Equal, "Expected = after indexed array element")?;
+
+        let value = self.require(Self::match_evaluable_expression, "Expected value after =")?;
+
+        Some(Expression::IndexAssignment(IndexAssignmentExpr {
+            name,
+            index,
+            value: Box::new(value),
+        }))
+    }
+
+    /// Parses `run!(command)`, whose result can only be stored with `let`.
+    fn match_run(&mut self) -> Option<Expression> {
+        if self
+            .match_token(Kind::Command)
+            .is_none_or(|token| token.lexeme != "run")
+        {
+            return None;
+        }
+
+        self.consume_token(Kind::Command)?;
+        self.expect_token(Kind::LeftParen, "Expected ( after run!")?;
+
+        let command = self.require(Self::match_fn_chain, "Expected a command to run")?;
+
+        self.expect_token(Kind::RightParen, "Expected ) after the command to run")?;
+
+        self.anonymous_counters += 1;
+
+        Some(Expression::Run(RunExpr {
+            command: Box::new(command),
+            id: self.anonymous_counters,
+        }))
+    }
+
+    fn match_identifier(&mut self) -> Option<Expression> {
+        if self.peek_token(Kind::Equal).is_some() {
+            let name = self.get_token()?.clone();
+            let previous = self.variables.get(&name.lexeme).copied();
+            let assignment = self.match_var_assignment()?;
+
+            if let Expression::VarAssignment(VarAssignmentExpr { value, .. }) = &assignment {
+                if matches!(value.as_ref(), Expression::Run(_)) {
+                    self.error(
+                        Diagnostic::error("The result of run!() can only be stored with let")
+                            .with_label(name.span, "assigned to an existing variable"),
+                    );
+                }
+
+                // Bash would store the literal in an indexed array
+                if matches!(value.as_ref(), Expression::Map(_)) && previous != Some(Type::Map) {
+                    self.error(
+                        Diagnostic::error(format!(
+                            "Cannot assign a map to \"{}\", which is not a map",
+                            name.lexeme
+                        ))
+                        .with_label(name.span, "not declared as a map")
+                        .with_note(format!("declare it with let {} = {{ ... }}", name.lexeme)),
+                    );
+                }
+            }
+
+            Some(assignment)
+        } else if self.peek_token(Kind::LeftBracket).is_some() {
+            self.match_index_assignment()
+        } else {
+            self.match_fn_chain()
+        }
+    }
+
+    fn match_fn_chain(&mut self) -> Option<Expression> {
+        let first = self.index;
+        let mut invocations = vec![self.match_fn_call()?];
+        let mut redirects: Vec<Redirect> = Vec::new();
+        let mut lines: Option<Token> = None;
+
+        while let Some(separator) =
+            self.consume_token_of_multiple_kinds(&[Kind::Period, Kind::PipeAmpersand])
+        {
+            if let Some(lines) = &lines {
+                let span = lines.span;
+                self.error(
+                    Diagnostic::error("lines() has to be the last method of a chain")
+                        .with_label(separator.span, "chain goes on after lines()")
+                        .with_secondary_label(span, "lines() is here"),
+                );
+            }
+
+            if separator.kind == Kind::Period && self.match_builtin_call(&["lines"]) {
+                lines = Some(self.consume_any_token());
+                self.expect_token(Kind::LeftParen, "Missing ( after function name")?;
+                self.expect_token(Kind::RightParen, "lines() takes no arguments")?;
+                continue;
+            }
+
+            if separator.kind == Kind::PipeAmpersand {
+                // `a |& b` is short for `a 2>&1 | b`
+                let redirect = Redirect {
+                    kind: RedirectKind::StderrToStdout,
+                    name: separator,
+                    target: None,
+                    stage: invocations.len() - 1,
+                };
+                self.check_redirect(&redirect, &redirects);
+                redirects.push(redirect);
+            } else if self.match_builtin_call(REDIRECTS) {
+                let redirect = self.match_redirect(invocations.len() - 1)?;
+                self.check_redirect(&redirect, &redirects);
+                redirects.push(redirect);
+                continue;
+            }
+
+            let start = self.index;
+            let invocation = if self.match_builtin_call(CHAIN_BUILTINS) {
+                self.match_chain_builtin()?
+            } else {
+                self.require(Self::match_fn_call, "Expected a command to pipe into")?
+            };
+
+            // Nothing is left to pipe into the next command
+            if let Some(output) = redirects.iter().find(|redirect| redirect.kind.is_stdout()) {
+                let span = self.span_since(start);
+                self.error(
+                    Diagnostic::error("Cannot pipe output that is redirected to a file")
+                        .with_label(span, "receives no input")
+                        .with_secondary_label(output.name.span, "output redirected here")
+                        .with_note(format!(
+                            "move .{}() to the end of the chain",
+                            output.name.lexeme
+                        )),
+                );
+            }
+
+            invocations.push(invocation);
+        }
+
+        // Statements and conditions save the statuses after the chain is parsed
+        if invocations.len() > 1 {
+            self.pipelines = true;
+            self.unsaved_statuses = Some(self.span_since(first));
+        }
+
+        Some(Expression::FnChain(FnChain {
+            invocations,
+            redirects,
+            lines: lines.is_some(),
+            save_statuses: false,
+        }))
+    }
+
+    /// Parses a text processing method of a chain, like `.filter(/^a/)`,
+    /// checking that its arguments are of the right kind.
+    fn match_chain_builtin(&mut self) -> Option<Expression> {
+        let name = self.consume_token(Kind::Identifier)?;
+        self.expect_token(Kind::LeftParen, "Missing ( after function name")?;
+
+        if name.lexeme == "map" {
+            let builtin = self.match_map_function()?;
+            self.expect_token(Kind::RightParen, "Missing ) after parameters list")?;
+
+            return Some(Expression::ChainBuiltin(ChainBuiltinExpr { name, builtin }));
+        }
+
+        let args = self.match_fn_arguments();
+        self.expect_token(Kind::RightParen, "Missing ) after parameters list")?;
+
+        let count = match args.as_slice() {
+            [count]
+                if matches!(
+                    types::infer(count, &self.variables),
+                    Type::Int | Type::Unknown
+                ) =>
+            {
+                Some(Box::new(count.clone()))
+            }
+            _ => None,
+        };
+
+        let expected = match (name.lexeme.as_str(), args.as_slice()) {
+            ("filter", [pattern]) => Ok(ChainBuiltin::Filter(Box::new(pattern.clone()))),
+            ("take", _) => count.map(ChainBuiltin::Take).ok_or("a single integer"),
+            ("skip", _) => count.map(ChainBuiltin::Skip).ok_or("a single integer"),
+            ("sort", []) => Ok(ChainBuiltin::Sort),
+            ("uniq", []) => Ok(ChainBuiltin::Uniq),
+            ("count", []) => Ok(ChainBuiltin::Count),
+            ("filter", _) => Err("a single regular expression"),
+            _ => Err("no arguments"),
+        };
+
+        match expected {
+            Ok(builtin) => Some(Expression::ChainBuiltin(ChainBuiltinExpr { name, builtin })),
+            Err(expected) => {
+                self.error(
+                    Diagnostic::error(format!("{}() expects {expected}", name.lexeme))
+                        .with_label(name.span, "wrong arguments"),
+                );
+                Some(Expression::Error(ErrorExpr { span: name.span }))
+            }
+        }
+    }
+
+    /// Parses the `line -> value` function of `.map()`, in which `line` is
+    /// bound to each line.
+    fn match_map_function(&mut self) -> Option<ChainBuiltin> {
+        let parameter = self.expect_token(
+            Kind::Identifier,
+            "Expected a parameter name, like line -> line",
+        )?;
+        self.expect_token(Kind::Arrow, "Expected -> after the parameter")?;
+
+        let shadowed = self
+            .variables
+            .insert(parameter.lexeme.clone(), Type::String);
+
+        let body = self.require(
+            Self::match_evaluable_expression,
+            "Expected a value after ->",
+        );
+
+        match shadowed {
+            Some(shadowed) => self.variables.insert(parameter.lexeme.clone(), shadowed),
+            None => self.variables.remove(&parameter.lexeme),
+        };
+
+        Some(ChainBuiltin::Map {
+            parameter,
+            body: Box::new(body?),
+        })
+    }
+
+    /// Parses a redirect of the command at index `stage` of a chain, like
+    /// `.to_file("out.log")`.
+    fn match_redirect(&mut self, stage: usize) -> Option<Redirect> {
+        let name = self.consume_token(Kind::Identifier)?;
+        let kind = RedirectKind::from_name(&name.lexeme)?;
+
+        self.expect_token(Kind::LeftParen, "Missing ( after function name")?;
+        let mut args = self.match_fn_arguments();
+        self.expect_token(Kind::RightParen, "Missing ) after parameters list")?;
+
+        let target = match (kind.takes_target(), args.len()) {
+            (true, 1) => args.pop().map(Box::new),
+            (false, 0) => None,
+            (takes_target, _) => {
+                let expected = if takes_target {
+                    "a single file name or string"
+                } else {
+                    "no arguments"
+                };
+
+                self.error(
+                    Diagnostic::error(format!("{}() expects {expected}", name.lexeme))
+                        .with_label(name.span, "wrong arguments"),
+                );
+                args.pop().map(Box::new)
+            }
+        };
+
+        Some(Redirect {
+            kind,
+            name,
+            target,
+            stage,
+        })
+    }
+
+    /// Reports a redirect that conflicts with the ones before it in the chain
+    /// or is not at a position where it makes sense.
+    fn check_redirect(&mut self, redirect: &Redirect, previous: &[Redirect]) {
+        let kind = redirect.kind;
+
+        let (message, conflict) = if kind.is_input() && redirect.stage > 0 {
+            (
+                "Only the first command of a chain can read from a file or string",
+                None,
+            )
+        } else if let Some(conflict) = previous.iter().find(|previous| {
+            (kind.is_input() && previous.kind.is_input())
+                || (previous.stage == redirect.stage
+                    && ((kind.is_stdout() && previous.kind.is_stdout())
+                        || (kind.is_stderr() && previous.kind.is_stderr())))
+        }) {
+            let message = if kind.is_input() {
+                "The input of the chain is already redirected"
+            } else if kind.is_stdout() {
+                "The output of the command is already redirected"
+            } else {
+                "The errors of the command are already redirected"
+            };
+            (message, Some(conflict.name.span))
+        } else {
+            return;
+        };
+
+        let method = match redirect.name.kind {
+            Kind::PipeAmpersand => redirect.name.lexeme.clone(),
+            _ => format!(".{}()", redirect.name.lexeme),
+        };
+
+        let mut diagnostic =
+            Diagnostic::error(message).with_label(redirect.name.span, format!("invalid {method}"));
+        if let Some(conflict) = conflict {
+            diagnostic = diagnostic.with_secondary_label(conflict, "redirected here");
+        }
+        self.error(diagnostic);
+    }
+
+    fn match_fn_call(&mut self) -> Option<Expression> {
+        let name = self.consume_token_of_multiple_kinds(&[Kind::Identifier, Kind::Command])?;
+
+        self.expect_token(Kind::LeftParen, "Missing ( after function name")?;
+
+        let args = self.match_fn_arguments();
+
+        self.expect_token(Kind::RightParen, "Missing ) after parameters list")?;
+
+        let command = matches!(name.kind, Kind::Command);
+
+        if command && name.lexeme == "run" {
+            self.error(
+                Diagnostic::error("The result of run!() can only be stored with let")
+                    .with_label(name.span, "not stored in a variable")
+                    .with_note("write let result = run!(command!()) and read result[\"code\"]"),
+            );
+        }
+
+        let user_defined = !command && self.functions.contains_key(&name.lexeme);
+
+        let fn_call = FnCall {
+            name,
+            args,
+            command,
+            user_defined,
+        };
+
+        if command {
+            return Some(Expression::FnCall(fn_call));
+        }
+
+        if user_defined {
+            let arity = self.functions[&fn_call.name.lexeme];
+
+            if fn_call.args.len() != arity {
+                self.error(
+                    Diagnostic::error(format!(
+                        "Function \"{}\" takes {} argument(s) but {} were given",
+                        fn_call.name.lexeme,
+                        arity,
+                        fn_call.args.len()
+                    ))
+                    .with_label(fn_call.name.span, "wrong number of arguments"),
+                );
+            }
+
+            return Some(Expression::FnCall(fn_call));
+        }
+
+        Some(self.match_builtin_fn_call(fn_call))
+    }
+
+    /// Turns calls to builtins that are not plain commands into their own
+    /// expressions, checking that their arguments are of the right kind.
+    fn match_builtin_fn_call(&mut self, fn_call: FnCall) -> Expression {
+        let span = fn_call.name.span;
+        let name = fn_call.name.lexeme.as_str();
+
+        let expected = match (name, fn_call.args.as_slice()) {
+            ("len", [value]) => {
+                return Expression::Length(LengthExpr {
+                    value: Box::new(value.clone()),
+                })
+            }
+            ("keys", [Expression::MapRef(MapRefExpr { name })]) => {
+                return Expression::Keys(KeysExpr { name: name.clone() })
+            }
+            ("has", [Expression::MapRef(MapRefExpr { name }), key]) => {
+                return Expression::Has(HasExpr {
+                    name: name.clone(),
+                    key: Box::new(key.clone()),
+                })
+            }
+            ("push", [Expression::ArrayRef(ArrayRefExpr { name }), values @ ..]) => {
+                return Expression::Push(PushExpr {
+                    name: name.clone(),
+                    values: values.to_vec(),
+                })
+            }
+            ("red" | "green" | "yellow" | "blue" | "magenta" | "cyan", [Expression::Value(_)]) => {
+                return Expression::FnCall(fn_call)
+            }
+            ("keys", _) => "a map",
+            ("has", _) => "a map and a key",
+            ("push", _) => "an array followed by values",
+            ("exit" | "wait_all", []) => return Expression::FnCall(fn_call),
+            ("exit", [code])
+                if matches!(
+                    types::infer(code, &self.variables),
+                    Type::Int | Type::Unknown
+                ) =>
+            {
+                return Expression::FnCall(fn_call)
+            }
+            ("exit", _) => "at most one integer",
+            ("wait", [job])
+                if matches!(
+                    types::infer(job, &self.variables),
+                    Type::Int | Type::Unknown
+                ) =>
+            {
+                return Expression::FnCall(fn_call)
+            }
+            ("wait", _) => "a single job",
+            ("wait_all", _) => "nothing",
+            ("len" | "red" | "green" | "yellow" | "blue" | "magenta" | "cyan", _) => {
+                "a single value"
+            }
+            _ if BUILTIN_FUNCTIONS.contains(&name) => return Expression::FnCall(fn_call),
+            _ => {
+                self.error(
+                    Diagnostic::error(format!("Unknown function \"{name}\""))
+                        .with_label(span, "not a builtin or declared function")
+                        .with_note(format!("to run a command, add ! after its name: {name}!()")),
+                );
+                return Expression::FnCall(fn_call);
+            }
+        };
+
+        self.error(
+            Diagnostic::error(format!("{name}() expects {expected} as its arguments"))
+                .with_label(span, "wrong arguments"),
+        );
+
+        Expression::FnCall(fn_call)
+    }
+
+    fn match_fn_arguments(&mut self) -> Vec<Expression> {
+        let mut args = Vec::new();
+
+        loop {
+            if let Some(arg) = self.consume_token_of_multiple_kinds(&[
+                Kind::String,
+                Kind::RawString,
+                Kind::Regex,
+                Kind::Option,
+            ]) {
+                args.push(Expression::Value(ValueExpr { value: arg }));
+            } else if let Some(string) = self.match_interpolated_string() {
+                args.push(string);
+            } else {
+                match self.match_arithmetic_expr() {
+                    Some(expr) => args.push(expr),
+                    None => break,
+                }
+            }
+
+            if self.consume_token(Kind::Coma).is_none() {
+                break;
+            }
+        }
+
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn parse(source: &str) -> Vec<Expression> {
+        let tokens = Lexer::from_string(source).parse().unwrap();
+        Parser::from_tokens(tokens).parse().unwrap()
+    }
+
+    /// Writes `expression` with every operation in parentheses.
+    fn grouped(expression: &Expression) -> String {
+        match expression {
+            Expression::Arithmetic(ArithmeticExpr {
+                lhs, operator, rhs, ..
+            })
+            | Expression::Logical(LogicalExpr { lhs, operator, rhs })
+            | Expression::Condition(ConditionExpr {
+                lhs, operator, rhs, ..
+            }) => format!("({} {} {})", grouped(lhs), operator.lexeme, grouped(rhs)),
+            Expression::Unary(UnaryExpr {
+                operator, operand, ..
+            }) => format!("({}{})", operator.lexeme, grouped(operand)),
+            Expression::Not(NotExpr { value }) => format!("(!{})", grouped(value)),
+            Expression::Value(ValueExpr { value }) => value.lexeme.clone(),
+            expression => expression.write(),
+        }
+    }
+
+    fn condition(source: &str) -> String {
+        match parse(&format!("if ({source}) {{\n}}")).as_slice() {
+            [Expression::IfStatement(statement)] => grouped(&statement.condition),
+            expressions => panic!("expected an if statement, found {:?}", expressions),
+        }
+    }
+
+    #[test]
+    fn logical_precedence() {
+        assert_eq!(
+            condition("1 < 2 || 2 < 3 && 3 < 4"),
+            "((1 < 2) || ((2 < 3) && (3 < 4)))"
+        );
+        assert_eq!(
+            condition("(1 < 2 || 2 < 3) && !(3 < 4)"),
+            "(((1 < 2) || (2 < 3)) && (!(3 < 4)))"
+        );
+        assert_eq!(condition("1 + 1 == 2"), "((1 + 1) == 2)");
+    }
+
+    fn arithmetic(source: &str) -> String {
+        match parse(&format!("let x = {source}")).as_slice() {
+            [Expression::VarDeclaration(declaration)] => grouped(&declaration.value),
+            expressions => panic!("expected a declaration, found {:?}", expressions),
+        }
+    }
+
+    #[test]
+    fn arithmetic_precedence() {
+        assert_eq!(arithmetic("1 + 2 * 3"), "(1 + (2 * 3))");
+        assert_eq!(arithmetic("1 * 2 + 3"), "((1 * 2) + 3)");
+        assert_eq!(arithmetic("1 << 2 + 3"), "(1 << (2 + 3))");
+        assert_eq!(arithmetic("1 | 2 ^ 3 & 4"), "(1 | (2 ^ (3 & 4)))");
+        assert_eq!(arithmetic("2 * 3 ** 2"), "(2 * (3 ** 2))");
+        assert_eq!(arithmetic("(1 + 2) * 3"), "((1 + 2) * 3)");
+    }
+
+    #[test]
+    fn arithmetic_associativity() {
+        assert_eq!(arithmetic("1 - 2 - 3"), "((1 - 2) - 3)");
+        assert_eq!(arithmetic("8 / 4 / 2"), "((8 / 4) / 2)");
+        assert_eq!(arithmetic("2 ** 3 ** 2"), "(2 ** (3 ** 2))");
+    }
+
+    #[test]
+    fn unary_operators() {
+        assert_eq!(arithmetic("-2 ** 2"), "(-(2 ** 2))");
+        assert_eq!(arithmetic("-2 * 3"), "(-2 * 3)");
+        assert_eq!(arithmetic("~1 + 1"), "((~1) + 1)");
+    }
+
+    #[test]
+    fn negated_variables() {
+        let expressions = parse("let a = 1\nlet b = 0 - -a % 3");
+        let [_, Expression::VarDeclaration(declaration)] = expressions.as_slice() else {
+            panic!("expected two declarations, found {:?}", expressions);
+        };
+        assert_eq!(grouped(&declaration.value), "(0 - ((-a) % 3))");
     }
 }
@@ -0,0 +1,141 @@
+//! Writing of sash values as bash words. Every function returns exactly one
+//! word, which is neither split on whitespace nor expanded as a glob.
+
+use std::fmt::Write;
+
+/// Characters that never need quoting in bash.
+fn is_safe(char: char) -> bool {
+    char.is_ascii_alphanumeric() || "_-+./:,=@%".contains(char)
+}
+
+/// Writes a reference to the value of the variable `name`.
+pub fn variable(name: &str) -> String {
+    format!("\"${name}\"")
+}
+
+/// Writes the output of the bash `code`, without its trailing newlines.
+pub fn command_substitution(code: &str) -> String {
+    format!("\"$({code})\"")
+}
+
+/// Writes `value` as a literal, which is left bare when it is safe, put in
+/// single quotes otherwise, and in `$'...'` when it has control characters.
+pub fn literal(value: &str) -> String {
+    if value.is_empty() {
+        "''".into()
+    } else if value.chars().all(is_safe) {
+        value.into()
+    } else if value.chars().any(char::is_control) {
+        ansi_c(value)
+    } else {
+        format!("'{}'", value.replace('\'', r"'\''"))
+    }
+}
+
+/// Writes `value` as a `$'...'` string, in which control characters are escaped.
+pub fn ansi_c(value: &str) -> String {
+    let mut output = String::from("$'");
+
+    for char in value.chars() {
+        match char {
+            '\n' => output.push_str(r"\n"),
+            '\t' => output.push_str(r"\t"),
+            '\r' => output.push_str(r"\r"),
+            '\x1b' => output.push_str(r"\e"),
+            '\'' => output.push_str(r"\'"),
+            '\\' => output.push_str(r"\\"),
+            char if char.is_control() => write!(output, r"\x{:02x}", u32::from(char)).unwrap(),
+            char => output.push(char),
+        }
+    }
+
+    output.push('\'');
+    output
+}
+
+/// Escapes `value` to be written literally between double quotes. Control
+/// characters are written as `$'...'` pieces between closed and reopened
+/// quotes, so that a new line in the value is not one in the bash code.
+pub fn escape_double_quoted(value: &str) -> String {
+    let mut output = String::new();
+    let mut controls = String::new();
+
+    for char in value.chars() {
+        if char.is_control() {
+            controls.push(char);
+            continue;
+        }
+
+        if !controls.is_empty() {
+            write!(output, "\"{}\"", ansi_c(&controls)).unwrap();
+            controls.clear();
+        }
+
+        if matches!(char, '\\' | '$' | '"' | '`') {
+            output.push('\\');
+        }
+        output.push(char);
+    }
+
+    if !controls.is_empty() {
+        write!(output, "\"{}\"", ansi_c(&controls)).unwrap();
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal_leaves_safe_values_bare() {
+        assert_eq!(literal("archive.tar.gz"), "archive.tar.gz");
+        assert_eq!(literal("-xf"), "-xf");
+        assert_eq!(literal("a=b,c@d:e"), "a=b,c@d:e");
+    }
+
+    #[test]
+    fn literal_quotes_empty_and_special_values() {
+        assert_eq!(literal(""), "''");
+        assert_eq!(literal("my notes.txt"), "'my notes.txt'");
+        assert_eq!(literal("*.rs"), "'*.rs'");
+        assert_eq!(literal("$HOME `id`"), "'$HOME `id`'");
+        assert_eq!(literal("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn literal_writes_control_characters_as_ansi_c() {
+        assert_eq!(literal("a\nb"), r"$'a\nb'");
+        assert_eq!(literal("tab\there"), r"$'tab\there'");
+    }
+
+    #[test]
+    fn ansi_c_escapes_quotes_backslashes_and_control_characters() {
+        assert_eq!(ansi_c("it's"), r"$'it\'s'");
+        assert_eq!(ansi_c(r"a\b"), r"$'a\\b'");
+        assert_eq!(ansi_c("\r\x1b[0m"), r"$'\r\e[0m'");
+        assert_eq!(ansi_c("\x07"), r"$'\x07'");
+    }
+
+    #[test]
+    fn escape_double_quoted_escapes_expansions() {
+        assert_eq!(
+            escape_double_quoted(r#"$5 "x" `y` \z"#),
+            r#"\$5 \"x\" \`y\` \\z"#
+        );
+        assert_eq!(escape_double_quoted("it's *"), "it's *");
+    }
+
+    #[test]
+    fn variable_and_command_substitution_are_quoted() {
+        assert_eq!(variable("name"), "\"$name\"");
+        assert_eq!(command_substitution("ls"), "\"$(ls)\"");
+    }
+
+    #[test]
+    fn escape_double_quoted_writes_control_characters_outside_of_the_quotes() {
+        assert_eq!(escape_double_quoted("a\nb"), r#"a"$'\n'"b"#);
+        assert_eq!(escape_double_quoted("a\t\n"), r#"a"$'\t\n'""#);
+    }
+}
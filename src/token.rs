@@ -1,8 +1,21 @@
+use crate::quoting;
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum Kind {
     Let,
     If,
     While,
+    Loop,
+    Do,
+    Break,
+    Continue,
+    For,
+    In,
+    Function,
+    Return,
+    Spawn,
+    Parallel,
+    Defer,
     Else,
     Equal,
     EqualEqual,
@@ -12,23 +25,59 @@ pub enum Kind {
     GreaterEqual,
     Not,
     NotEqual,
+    And,
+    Or,
     Plus,
     Minus,
     Asterisk,
     Slash,
+    Percent,
+    Power,
+    Ampersand,
+    Pipe,
+    /// `|&`, which pipes stderr along with stdout into the next command.
+    PipeAmpersand,
+    Caret,
+    Tilde,
+    ShiftLeft,
+    ShiftRight,
     Identifier,
     Command,
     Comment,
     String,
+    /// Text of a string up to the `{` of its first interpolated expression.
+    InterpolationStart,
+    /// Text of a string between two interpolated expressions.
+    InterpolationMiddle,
+    /// Text of a string after the `}` of its last interpolated expression.
+    InterpolationEnd,
     RawString,
+    /// A regular expression between slashes, like `/^a+$/`.
+    Regex,
     Number,
+    Float,
+    Boolean,
+    /// The exit status of the last command.
+    Status,
+    /// The exit statuses of the commands of the last pipeline.
+    Statuses,
     LeftParen,
     RightParen,
+    LeftBrace,
+    RightBrace,
     LeftBracket,
     RightBracket,
     Coma,
+    Colon,
     Period,
+    Range,
     Option,
+    /// `->`, between the parameter and the body of a function like in `.map(s -> s)`.
+    Arrow,
+    /// End of a line that ends a statement. Lines that continue an
+    /// expression do not produce one, see `Lexer::match_new_line`.
+    Newline,
+    Semicolon,
 }
 
 impl Kind {
@@ -36,61 +85,142 @@ impl Kind {
         match char {
             '(' => Some(Self::LeftParen),
             ')' => Some(Self::RightParen),
-            '{' => Some(Self::LeftBracket),
-            '}' => Some(Self::RightBracket),
+            '{' => Some(Self::LeftBrace),
+            '}' => Some(Self::RightBrace),
+            '[' => Some(Self::LeftBracket),
+            ']' => Some(Self::RightBracket),
             ',' => Some(Self::Coma),
-            '.' => Some(Self::Period),
+            ':' => Some(Self::Colon),
+            ';' => Some(Self::Semicolon),
+            // '.' => Some(Self::Period), See Lexer::match_char_token
             '+' => Some(Self::Plus),
             // '-' => Some(Self::Minus), See Lexer::match_option
-            '*' => Some(Self::Asterisk),
-            '/' => Some(Self::Slash),
+            // '*' => Some(Self::Asterisk), See Lexer::match_char_token
+            // '/' => Some(Self::Slash), See Lexer::match_slash
+            '%' => Some(Self::Percent),
+            '^' => Some(Self::Caret),
+            '~' => Some(Self::Tilde),
+            _ => None,
+        }
+    }
+
+    /// Binding power of the kind as a binary arithmetic operator, from the
+    /// loosest `|` to the tightest `**`. Comparisons bind looser than all of them.
+    pub fn precedence(self) -> Option<u8> {
+        match self {
+            Self::Pipe => Some(1),
+            Self::Caret => Some(2),
+            Self::Ampersand => Some(3),
+            Self::ShiftLeft | Self::ShiftRight => Some(4),
+            Self::Plus | Self::Minus => Some(5),
+            Self::Asterisk | Self::Slash | Self::Percent => Some(6),
+            Self::Power => Some(8),
             _ => None,
         }
     }
 
+    pub fn is_right_associative(self) -> bool {
+        matches!(self, Self::Power)
+    }
+
+    /// Whether an expression goes on after a token of this kind at the end
+    /// of a line, like a binary operator waiting for its right operand.
+    pub fn continues_line(self) -> bool {
+        self.precedence().is_some()
+            || matches!(
+                self,
+                Self::Equal
+                    | Self::EqualEqual
+                    | Self::NotEqual
+                    | Self::Less
+                    | Self::LessEqual
+                    | Self::Greater
+                    | Self::GreaterEqual
+                    | Self::And
+                    | Self::Or
+                    | Self::Not
+                    | Self::Tilde
+                    | Self::Coma
+                    | Self::Colon
+                    | Self::Period
+                    | Self::PipeAmpersand
+                    | Self::Range
+            )
+    }
+
+    /// Whether the kind ends a statement.
+    pub fn is_separator(self) -> bool {
+        matches!(self, Self::Newline | Self::Semicolon)
+    }
+
     fn from_keyword(str: &str) -> Option<Self> {
         match str {
             "let" => Some(Self::Let),
             "if" => Some(Self::If),
             "else" => Some(Self::Else),
             "while" => Some(Self::While),
+            "loop" => Some(Self::Loop),
+            "do" => Some(Self::Do),
+            "break" => Some(Self::Break),
+            "continue" => Some(Self::Continue),
+            "for" => Some(Self::For),
+            "in" => Some(Self::In),
+            "function" => Some(Self::Function),
+            "return" => Some(Self::Return),
+            "spawn" => Some(Self::Spawn),
+            "parallel" => Some(Self::Parallel),
+            "defer" => Some(Self::Defer),
+            "true" | "false" => Some(Self::Boolean),
+            "status" => Some(Self::Status),
+            "statuses" => Some(Self::Statuses),
             _ => None,
         }
     }
 }
 
+/// Binding power of the unary `-` and `~` operators, see `Kind::precedence`.
+pub const UNARY_PRECEDENCE: u8 = 7;
+
+/// Location of a token in the source: the byte range it covers and the
+/// line and column, both starting at 1, of its first character.
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
 #[derive(Debug, Clone)]
 pub struct Token {
     pub lexeme: String,
     pub kind: Kind,
+    pub span: Span,
 }
 
 impl Token {
+    pub fn new(lexeme: impl Into<String>, kind: Kind) -> Self {
+        Token {
+            lexeme: lexeme.into(),
+            kind,
+            span: Span::default(),
+        }
+    }
+
     pub fn write(&self) -> String {
         match self.kind {
-            Kind::String => format!("\"{}\"", self.lexeme),
-            Kind::Identifier => format!("${}", self.lexeme),
-            Kind::Less => "-lt".into(),
-            Kind::LessEqual => "-lte".into(),
-            Kind::Greater => "-gt".into(),
-            Kind::GreaterEqual => "-gte".into(),
-            Kind::EqualEqual => "-eq".into(),
-            Kind::NotEqual => "-ne".into(),
+            Kind::String | Kind::Regex => quoting::literal(&self.lexeme),
+            Kind::Identifier => quoting::variable(&self.lexeme),
+            Kind::Status => quoting::variable("?"),
             _ => self.lexeme.clone(),
         }
     }
 
     pub fn from_char(char: char) -> Option<Self> {
-        Some(Token {
-            lexeme: char.into(),
-            kind: Kind::from_char(char)?,
-        })
+        Some(Token::new(char, Kind::from_char(char)?))
     }
 
     pub fn from_keyword(str: &str) -> Option<Self> {
-        Some(Token {
-            lexeme: str.into(),
-            kind: Kind::from_keyword(str)?,
-        })
+        Some(Token::new(str, Kind::from_keyword(str)?))
     }
 }
@@ -0,0 +1,109 @@
+use std::{collections::HashMap, fmt};
+
+#[allow(clippy::wildcard_imports)]
+use crate::{expression::*, token::Kind};
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Type {
+    Int,
+    Float,
+    String,
+    Bool,
+    Array,
+    Map,
+    Unknown,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Int => "integer",
+            Self::Float => "float",
+            Self::String => "string",
+            Self::Bool => "boolean",
+            Self::Array => "array",
+            Self::Map => "map",
+            Self::Unknown => "unknown",
+        })
+    }
+}
+
+/// How two operands of a comparison are compared with each other.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Comparison {
+    Numeric,
+    /// Numeric with a float on either side, which bash cannot compare.
+    Float,
+    String,
+}
+
+/// Infers the type of value that `expression` evaluates to, looking up
+/// variables in `variables`. Anything that cannot be known at compile time,
+/// like the output of a command, is `Type::Unknown`.
+#[allow(clippy::implicit_hasher)]
+pub fn infer(expression: &Expression, variables: &HashMap<String, Type>) -> Type {
+    match expression {
+        Expression::Value(ValueExpr { value }) => match value.kind {
+            Kind::Number | Kind::Status => Type::Int,
+            Kind::Float => Type::Float,
+            Kind::String | Kind::RawString | Kind::Regex => Type::String,
+            Kind::Boolean => Type::Bool,
+            Kind::Identifier => variables
+                .get(&value.lexeme)
+                .copied()
+                .unwrap_or(Type::Unknown),
+            _ => Type::Unknown,
+        },
+        Expression::Arithmetic(ArithmeticExpr { float: true, .. })
+        | Expression::Unary(UnaryExpr { float: true, .. }) => Type::Float,
+        Expression::Arithmetic(_)
+        | Expression::Unary(_)
+        | Expression::Length(_)
+        | Expression::Spawn(_) => Type::Int,
+        Expression::FnCall(call) if call.waits() => Type::Int,
+        Expression::InterpolatedString(_) => Type::String,
+        Expression::FnChain(FnChain { lines: true, .. })
+        | Expression::Array(_)
+        | Expression::ArrayRef(_)
+        | Expression::Slice(_)
+        | Expression::Keys(_) => Type::Array,
+        Expression::Map(_) | Expression::MapRef(_) | Expression::Run(_) => Type::Map,
+        Expression::Condition(_)
+        | Expression::Logical(_)
+        | Expression::Not(_)
+        | Expression::Boolean(_)
+        | Expression::Has(_)
+        | Expression::FunctionCondition(_) => Type::Bool,
+        _ => Type::Unknown,
+    }
+}
+
+/// Decides how a comparison of values of types `lhs` and `rhs` is lowered.
+///
+/// Unknown operands take the type of the other side. When neither side is
+/// known, ordering falls back to numbers and equality to strings, which is
+/// correct for both kinds of values as long as numbers are written canonically.
+///
+/// # Errors
+///
+/// Returns a message describing the mismatch when the types cannot be compared.
+pub fn resolve_comparison(lhs: Type, rhs: Type, operator: Kind) -> Result<Comparison, String> {
+    match (lhs, rhs) {
+        (Type::Int, Type::Int | Type::Unknown) | (Type::Unknown, Type::Int) => {
+            Ok(Comparison::Numeric)
+        }
+        (Type::Float, Type::Int | Type::Float | Type::Unknown)
+        | (Type::Int | Type::Unknown, Type::Float) => Ok(Comparison::Float),
+        (Type::String | Type::Bool, Type::Unknown)
+        | (Type::Unknown, Type::String | Type::Bool)
+        | (Type::String, Type::String) => Ok(Comparison::String),
+        (Type::Bool, Type::Bool) if matches!(operator, Kind::EqualEqual | Kind::NotEqual) => {
+            Ok(Comparison::String)
+        }
+        (Type::Unknown, Type::Unknown) => match operator {
+            Kind::EqualEqual | Kind::NotEqual => Ok(Comparison::String),
+            _ => Ok(Comparison::Numeric),
+        },
+        (lhs, rhs) => Err(format!("Type error: Cannot compare {lhs} with {rhs}")),
+    }
+}